@@ -0,0 +1,160 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{library::Crate, macro_deps::MacroDep};
+
+/// The version of `Index`, to be bumped whenever what it holds changes, so
+/// that the indexes stored by older bundlers are not loaded.
+const FORMAT_VERSION: u32 = 2;
+
+/// Index of the library saved between runs, so that we need not parse
+/// every manifest and `src/lib.rs` each time.
+///
+/// It is invalidated when any of the files it was built from is modified,
+/// or when `FORMAT_VERSION` differs.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Index {
+    version: u32,
+    root: PathBuf,
+    // the files read to build the index, with their mtimes
+    files: Vec<(PathBuf, SystemTime)>,
+    // maps with non-string keys are not allowed in JSON
+    pub(crate) ident_crate: Vec<(Vec<String>, Crate)>,
+    pub(crate) crate_path: Vec<(Crate, PathBuf)>,
+    pub(crate) deps_1: Vec<(Crate, Vec<Crate>)>,
+    pub(crate) macro_exports: Vec<(Crate, Vec<String>)>,
+    pub(crate) features: Vec<(Crate, BTreeSet<String>)>,
+    pub(crate) macro_deps: Vec<MacroDep>,
+}
+
+impl Index {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        root: &Path,
+        files: impl IntoIterator<Item = PathBuf>,
+        ident_crate: &BTreeMap<Vec<String>, Crate>,
+        crate_path: &BTreeMap<Crate, PathBuf>,
+        deps_1: &BTreeMap<Crate, Vec<Crate>>,
+        macro_exports: &BTreeMap<Crate, Vec<String>>,
+        features: &BTreeMap<Crate, BTreeSet<String>>,
+        macro_deps: &[MacroDep],
+    ) -> Option<Self> {
+        let files = files
+            .into_iter()
+            .map(|path| Some((path.clone(), mtime(&path)?)))
+            .collect::<Option<_>>()?;
+        fn pairs<K: Clone, V: Clone>(map: &BTreeMap<K, V>) -> Vec<(K, V)> {
+            map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        }
+        Some(Self {
+            version: FORMAT_VERSION,
+            root: root.to_owned(),
+            files,
+            ident_crate: pairs(ident_crate),
+            crate_path: pairs(crate_path),
+            deps_1: pairs(deps_1),
+            macro_exports: pairs(macro_exports),
+            features: pairs(features),
+            macro_deps: macro_deps.to_vec(),
+        })
+    }
+
+    /// Loads the index of the library at `root` from `dir`, unless it is
+    /// missing or outdated.
+    pub(crate) fn load(dir: &Path, root: &Path) -> Option<Self> {
+        let json = fs::read_to_string(index_path(dir, root)).ok()?;
+        let index: Self = serde_json::from_str(&json).ok()?;
+        let fresh = index.version == FORMAT_VERSION
+            && index.root == root
+            && index.files.iter().all(|(path, t)| mtime(path) == Some(*t));
+        fresh.then_some(index)
+    }
+
+    /// Returns the files the index is built from.
+    pub(crate) fn files(&self) -> Vec<PathBuf> {
+        self.files.iter().map(|(path, _)| path.clone()).collect()
+    }
+
+    /// Saves the index into `dir`.  Failures are ignored, as the index is
+    /// only a cache.
+    pub(crate) fn store(&self, dir: &Path) {
+        let path = index_path(dir, &self.root);
+        // write and rename, so that concurrent runs never see a partial one
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        let json = serde_json::to_string(self).unwrap();
+        if fs::create_dir_all(dir).is_ok() && fs::write(&tmp, json).is_ok() {
+            fs::rename(&tmp, &path).unwrap_or_else(|_| {
+                let _ = fs::remove_file(&tmp);
+            });
+        }
+    }
+}
+
+/// `$XDG_CACHE_HOME/nekolib-bundle`, or `~/.cache/nekolib-bundle`.
+pub fn default_dir() -> Option<PathBuf> {
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| Some(home::home_dir()?.join(".cache")))?;
+    Some(cache.join("nekolib-bundle"))
+}
+
+fn index_path(dir: &Path, root: &Path) -> PathBuf {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    root.hash(&mut hasher);
+    dir.join(format!("index-{:016x}.json", hasher.finish()))
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[test]
+fn invalidation() {
+    let dir = crate::temp::TempDir::new("cache").unwrap();
+    let dir = dir.path();
+    let root = dir.join("nekolib-doc");
+    fs::create_dir_all(&root).unwrap();
+    let lib = root.join("lib.rs");
+    fs::write(&lib, "").unwrap();
+
+    let crate_path = BTreeMap::from([(
+        Crate::new("ds".to_owned(), "fenwick".to_owned()),
+        lib.clone(),
+    )]);
+    let index = Index::new(
+        &root,
+        [lib.clone()],
+        &BTreeMap::new(),
+        &crate_path,
+        &BTreeMap::new(),
+        &BTreeMap::new(),
+        &BTreeMap::new(),
+        &[],
+    )
+    .unwrap();
+    index.store(dir);
+
+    let loaded = Index::load(dir, &root).map(|index| index.crate_path);
+    let other = Index::load(dir, dir);
+    let mut newer = Index::load(dir, &root).unwrap();
+    newer.version = FORMAT_VERSION + 1;
+    newer.store(dir);
+    let newer = Index::load(dir, &root);
+    index.store(dir);
+    let file = fs::File::options().write(true).open(&lib).unwrap();
+    file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+    let modified = Index::load(dir, &root);
+
+    assert_eq!(loaded, Some(crate_path.into_iter().collect()));
+    assert!(other.is_none());
+    assert!(newer.is_none());
+    assert!(modified.is_none());
+}
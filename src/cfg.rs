@@ -0,0 +1,223 @@
+use std::collections::BTreeSet;
+
+use syn::{Meta, Token, punctuated::Punctuated};
+
+/// Configuration options that depend on the judge, which we cannot know.
+const TARGET_NAMES: &[&str] = &[
+    "debug_assertions",
+    "overflow_checks",
+    "panic",
+    "relocation_model",
+    "target_abi",
+    "target_arch",
+    "target_endian",
+    "target_env",
+    "target_family",
+    "target_feature",
+    "target_has_atomic",
+    "target_os",
+    "target_pointer_width",
+    "target_vendor",
+    "unix",
+    "windows",
+];
+
+/// Configuration options set for bundling.
+///
+/// `test` is never set unless specified.  Options depending on the judge,
+/// such as `target_os`, are unknown unless specified, and the others are
+/// set only if specified.  Once `name = "value"` is specified, `name` with
+/// the other values is not set, except for `feature` and `target_feature`.
+#[derive(Clone, Debug, Default)]
+pub struct Cfg {
+    // `name` or `name = "value"`
+    set: BTreeSet<(String, Option<String>)>,
+}
+
+impl Cfg {
+    pub fn new(
+        set: impl IntoIterator<Item = (String, Option<String>)>,
+    ) -> Self {
+        Self { set: set.into_iter().collect() }
+    }
+
+    /// Returns the options with `feature = "..."` set for each of
+    /// `features`, as Cargo does for the enabled features of a crate.
+    pub fn with_features(
+        &self,
+        features: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        let mut res = self.clone();
+        res.set.extend(
+            features
+                .into_iter()
+                .map(|f| ("feature".to_owned(), Some(f.to_string()))),
+        );
+        res
+    }
+
+    /// Parses an option in the form of `--cfg`, e.g. `unix` or
+    /// `target_os="linux"`.
+    pub fn parse_option(s: &str) -> Result<(String, Option<String>), String> {
+        let error = || {
+            format!("invalid cfg `{s}`; expected `name` or `name=\"value\"`")
+        };
+        let (name, value) = match s.split_once('=') {
+            Some((name, value)) => {
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                (name.trim(), Some(value.to_owned()))
+            }
+            None => (s.trim(), None),
+        };
+        syn::parse_str::<syn::Ident>(name).map_err(|_| error())?;
+        Ok((name.to_owned(), value))
+    }
+
+    /// Evaluates a configuration predicate such as `all(unix, not(test))`.
+    /// Returns `None` if the result depends on unknown options.
+    pub fn eval(&self, pred: &Meta) -> Option<bool> {
+        match pred {
+            Meta::Path(path) => {
+                self.option(&path.get_ident()?.to_string(), None)
+            }
+            Meta::NameValue(nv) => {
+                let syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(value),
+                    ..
+                }) = &nv.value
+                else {
+                    return None;
+                };
+                let name = nv.path.get_ident()?.to_string();
+                self.option(&name, Some(value.value()))
+            }
+            Meta::List(list) => {
+                let op = list.path.get_ident()?.to_string();
+                let args = list
+                    .parse_args_with(
+                        Punctuated::<Meta, Token![,]>::parse_terminated,
+                    )
+                    .ok()?;
+                let values: Vec<_> =
+                    args.iter().map(|arg| self.eval(arg)).collect();
+                match op.as_str() {
+                    "all" if values.contains(&Some(false)) => Some(false),
+                    "all" if values.contains(&None) => None,
+                    "all" => Some(true),
+                    "any" if values.contains(&Some(true)) => Some(true),
+                    "any" if values.contains(&None) => None,
+                    "any" => Some(false),
+                    "not" if values.len() == 1 => values[0].map(|v| !v),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// Applies `#[cfg]`, `#[cfg_attr]` and `#[test]` in `attrs`, and returns
+    /// `false` if the item is to be removed.
+    ///
+    /// `#[cfg]`s that hold are removed, and `#[cfg_attr]`s that hold are
+    /// expanded.  The ones depending on unknown options are kept as is.
+    pub fn apply(&self, attrs: &mut Vec<syn::Attribute>) -> bool {
+        let mut res = vec![];
+        let mut stack: Vec<_> = attrs.drain(..).rev().collect();
+        while let Some(attr) = stack.pop() {
+            let meta = &attr.meta;
+            let value = match meta {
+                Meta::Path(path) if path.is_ident("test") => {
+                    self.option("test", None)
+                }
+                Meta::List(list) if list.path.is_ident("cfg") => {
+                    list.parse_args().ok().and_then(|pred| self.eval(&pred))
+                }
+                Meta::List(list) if list.path.is_ident("cfg_attr") => {
+                    let args = list.parse_args_with(
+                        Punctuated::<Meta, Token![,]>::parse_terminated,
+                    );
+                    let mut args = args.into_iter().flatten();
+                    match args.next().and_then(|pred| self.eval(&pred)) {
+                        Some(true) => {
+                            let args: Vec<_> = args.collect();
+                            stack.extend(args.into_iter().rev().map(|meta| {
+                                syn::Attribute { meta, ..attr.clone() }
+                            }));
+                        }
+                        Some(false) => {}
+                        None => res.push(attr),
+                    }
+                    continue;
+                }
+                _ => {
+                    res.push(attr);
+                    continue;
+                }
+            };
+            match value {
+                Some(false) => return false,
+                // `#[test]` is kept if `test` is set
+                Some(true) if matches!(meta, Meta::Path(_)) => res.push(attr),
+                Some(true) => {}
+                None => res.push(attr),
+            }
+        }
+        *attrs = res;
+        true
+    }
+
+    /// Returns whether `#[cfg]`s in `attrs` make the item removed.
+    pub fn is_disabled(&self, attrs: &[syn::Attribute]) -> bool {
+        !self.apply(&mut attrs.to_vec())
+    }
+
+    fn option(&self, name: &str, value: Option<String>) -> Option<bool> {
+        const MULTI: &[&str] = &["feature", "target_feature"];
+
+        if self.set.contains(&(name.to_owned(), value.clone())) {
+            Some(true)
+        } else if value.is_some()
+            && !MULTI.contains(&name)
+            && self.set.iter().any(|(n, v)| n == name && v.is_some())
+        {
+            Some(false)
+        } else if TARGET_NAMES.contains(&name) {
+            None
+        } else {
+            Some(false)
+        }
+    }
+}
+
+#[test]
+fn evaluation() {
+    let cfg = Cfg::default();
+    let eval = |s: &str| cfg.eval(&syn::parse_str(s).unwrap());
+
+    assert_eq!(eval("test"), Some(false));
+    assert_eq!(eval("not(test)"), Some(true));
+    assert_eq!(eval("unix"), None);
+    assert_eq!(eval("all(unix, test)"), Some(false));
+    assert_eq!(eval("all(unix, not(test))"), None);
+    assert_eq!(eval("any(unix, not(test))"), Some(true));
+    assert_eq!(eval("any(unix, test)"), None);
+    assert_eq!(eval("any(test, all(test, unix))"), Some(false));
+    assert_eq!(eval(r#"target_os = "linux""#), None);
+    assert_eq!(eval(r#"feature = "std""#), Some(false));
+
+    let cfg = Cfg::new([
+        Cfg::parse_option(r#"target_os="linux""#).unwrap(),
+        Cfg::parse_option("feature = std").unwrap(),
+        Cfg::parse_option("unix").unwrap(),
+    ]);
+    let eval = |s: &str| cfg.eval(&syn::parse_str(s).unwrap());
+    assert_eq!(eval(r#"target_os = "linux""#), Some(true));
+    assert_eq!(eval(r#"target_os = "windows""#), Some(false));
+    assert_eq!(eval(r#"target_pointer_width = "64""#), None);
+    assert_eq!(eval(r#"all(unix, feature = "std")"#), Some(true));
+    assert_eq!(eval(r#"feature = "alloc""#), Some(false));
+    assert!(Cfg::parse_option("a b").is_err());
+}
@@ -0,0 +1,130 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    edition::Edition,
+    error::BundleError,
+    library::{Bundled, Crate},
+    resolve::Origin,
+    temp::TempDir,
+};
+
+pub struct CheckOptions {
+    pub rustc: PathBuf,
+    pub edition: Edition,
+    pub target: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// `(line, column)` in the bundled code, both 1-indexed.
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+    /// The library crate the location belongs to, along with the original
+    /// file and line if known.
+    pub origin: Option<(Crate, Option<Origin>)>,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some((line, column)) = self.location {
+            write!(f, "{line}:{column}: ")?;
+        }
+        write!(f, "{}", self.message)?;
+        match &self.origin {
+            Some((cr, Some((path, line)))) => {
+                write!(f, " (in `{cr}`, {}:{line})", path.display())?
+            }
+            Some((cr, None)) => write!(f, " (in `{cr}`)")?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// Compiles the bundled code with `rustc` and reports the errors.
+pub fn check(
+    bundled: &Bundled,
+    options: &CheckOptions,
+) -> Result<(), BundleError> {
+    let dir = TempDir::new("check")?;
+    let src = dir.path().join("main.rs");
+    fs::write(&src, bundled.get_code())
+        .map_err(|e| BundleError::io("write", &src, e))?;
+
+    let mut command = Command::new(&options.rustc);
+    command
+        .arg("--edition")
+        .arg(options.edition.to_string())
+        .args(["--crate-type", "bin", "--emit", "metadata"])
+        .args(["--error-format", "short", "--out-dir"])
+        .arg(dir.path())
+        .arg(&src);
+    if let Some(target) = &options.target {
+        command.args(["--target", target]);
+    }
+    let output = command.output().map_err(|e| BundleError::Rustc {
+        message: format!("{}: {e}", options.rustc.display()),
+    })?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut diagnostics: Vec<_> =
+        stderr.lines().filter_map(|line| parse_short(line, &src)).collect();
+    if diagnostics.is_empty() {
+        // e.g. unknown target; report as is
+        diagnostics.extend(stderr.lines().map(|line| Diagnostic {
+            location: None,
+            message: line.to_owned(),
+            origin: None,
+        }));
+    }
+    for d in &mut diagnostics {
+        d.origin = d.location.and_then(|(line, _)| {
+            let (cr, origin) = bundled.origin(line)?;
+            Some((cr.clone(), origin.cloned()))
+        });
+    }
+
+    Err(BundleError::CheckFailed { diagnostics })
+}
+
+/// Parses an error of `--error-format=short`, such as
+/// `/tmp/main.rs:3:5: error[E0425]: cannot find value `x` in this scope`.
+fn parse_short(line: &str, src: &Path) -> Option<Diagnostic> {
+    let rest = line.strip_prefix(src.to_str()?)?.strip_prefix(':')?;
+    let mut it = rest.splitn(3, ':');
+    let line = it.next()?.parse().ok()?;
+    let column = it.next()?.parse().ok()?;
+    let message = it.next()?.trim_start();
+    message.starts_with("error").then(|| Diagnostic {
+        location: Some((line, column)),
+        message: message.to_owned(),
+        origin: None,
+    })
+}
+
+#[test]
+fn short_format() {
+    let src = Path::new("/tmp/nekolib-bundle/main.rs");
+    let error = "/tmp/nekolib-bundle/main.rs:3:5: error[E0425]: cannot find value `x` in this scope";
+    let warning =
+        "/tmp/nekolib-bundle/main.rs:1:5: warning: unused import: `std::io`";
+
+    let actual = parse_short(error, src).unwrap();
+    assert_eq!(actual.location, Some((3, 5)));
+    assert_eq!(
+        actual.message,
+        "error[E0425]: cannot find value `x` in this scope"
+    );
+    assert!(parse_short(warning, src).is_none());
+    assert!(
+        parse_short("error: aborting due to 1 previous error", src).is_none()
+    );
+}
@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+
+use crate::check::Diagnostic;
+
+#[derive(Debug)]
+pub enum BundleError {
+    /// `{root}::{path}` is not exported by any crate of the library `root`.
+    UnknownIdent {
+        root: String,
+        path: Vec<String>,
+        suggestions: Vec<Vec<String>>,
+    },
+    /// Crates depend on each other, as `[a, b, .., a]`.
+    DependencyCycle {
+        cycle: Vec<String>,
+    },
+    ManifestParse {
+        path: PathBuf,
+        source: cargo_toml::Error,
+    },
+    /// `path` is `None` for the user's source.
+    SourceParse {
+        path: Option<PathBuf>,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// `#[cfg_attr(pred, path = "..")]` of `mod foo;` depends on an option
+    /// not given, so the file of the module cannot be determined.
+    UnknownCfg {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        pred: String,
+    },
+    /// The file for `mod foo;` could not be read.
+    MissingModule {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// `op`, as `read` or `write`, failed on `path`.
+    Io {
+        op: &'static str,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// `path` is not a crate in a category directory, as
+    /// `nekolib-src/ds/fenwick`.
+    CrateLayout {
+        path: PathBuf,
+    },
+    Git {
+        message: String,
+    },
+    /// `rustc` could not be run.
+    Rustc {
+        message: String,
+    },
+    /// `rustfmt` could not be run or failed.
+    Rustfmt {
+        message: String,
+    },
+    /// The files could not be watched.
+    Watch {
+        message: String,
+    },
+    /// The output of `--watch` is the source or inside a library, so
+    /// writing it would trigger another run.
+    WatchedOutput {
+        path: PathBuf,
+    },
+    /// The source map could not be written as JSON.
+    SourceMap {
+        message: String,
+    },
+    /// No module bundled by a previous run is found in the source.
+    NotBundled {
+        path: PathBuf,
+    },
+    /// The bundled code does not compile.
+    CheckFailed {
+        diagnostics: Vec<Diagnostic>,
+    },
+}
+
+impl BundleError {
+    pub(crate) fn source_parse(path: Option<&Path>, e: syn::Error) -> Self {
+        let start = e.span().start();
+        Self::SourceParse {
+            path: path.map(Path::to_owned),
+            line: start.line,
+            column: start.column + 1,
+            message: e.to_string(),
+        }
+    }
+
+    pub fn io(op: &'static str, path: &Path, source: std::io::Error) -> Self {
+        Self::Io { op, path: path.to_owned(), source }
+    }
+}
+
+impl std::fmt::Display for BundleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownIdent { root, path, suggestions } => {
+                write!(f, "unresolved path `{root}::{}`", path.join("::"))?;
+                for (i, s) in suggestions.iter().enumerate() {
+                    let help = if i == 0 { "did you mean" } else { "  or" };
+                    write!(f, "\n  help: {help} `{root}::{}`?", s.join("::"))?;
+                }
+                Ok(())
+            }
+            Self::DependencyCycle { cycle } => {
+                write!(f, "dependency cycle: {}", cycle.join(" -> "))?;
+                write!(
+                    f,
+                    "\n  help: pass `--allow-cycles` to bundle them together"
+                )
+            }
+            Self::ManifestParse { path, source } => {
+                write!(f, "failed to parse {}: {source}", path.display())
+            }
+            Self::SourceParse { path, line, column, message } => {
+                let path = path
+                    .as_ref()
+                    .map_or("<source>".into(), |p| p.display().to_string());
+                write!(f, "{path}:{line}:{column}: {message}")
+            }
+            Self::UnknownCfg { path, line, column, pred } => {
+                let path = path.display();
+                write!(
+                    f,
+                    "{path}:{line}:{column}: cannot tell whether `{pred}` holds"
+                )?;
+                write!(f, "\n  help: set the option with `--cfg`")
+            }
+            Self::MissingModule { path, source } => {
+                write!(f, "failed to read module {}: {source}", path.display())
+            }
+            Self::Io { op, path, source } => {
+                write!(f, "failed to {op} {}: {source}", path.display())
+            }
+            Self::CrateLayout { path } => {
+                let path = path.display();
+                write!(f, "{path} is not a crate in a category directory")
+            }
+            Self::Git { message } => write!(f, "git failed: {message}"),
+            Self::Rustc { message } => {
+                write!(f, "failed to run rustc: {message}")
+            }
+            Self::Rustfmt { message } => {
+                write!(f, "failed to run rustfmt: {message}")
+            }
+            Self::Watch { message } => {
+                write!(f, "failed to watch files: {message}")
+            }
+            Self::WatchedOutput { path } => {
+                let path = path.display();
+                write!(f, "cannot write to {path}, which is watched itself")?;
+                write!(
+                    f,
+                    "\n  help: write outside the source and the libraries"
+                )
+            }
+            Self::SourceMap { message } => {
+                write!(f, "failed to write the source map: {message}")
+            }
+            Self::NotBundled { path } => {
+                write!(f, "no bundled module found in {}", path.display())
+            }
+            Self::CheckFailed { diagnostics } => {
+                write!(f, "bundled code failed to compile")?;
+                for d in diagnostics {
+                    write!(f, "\n  {d}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for BundleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ManifestParse { source, .. } => Some(source),
+            Self::MissingModule { source, .. } | Self::Io { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+}
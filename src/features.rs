@@ -0,0 +1,224 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use cargo_toml::Manifest;
+
+use crate::error::BundleError;
+
+/// Features and dependencies enabled in each package of the library.
+///
+/// They are resolved from the root down as Cargo does: `default` features
+/// unless `default-features = false`, features requested by dependents,
+/// and optional dependencies activated by `dep:name`, `name/feature` or
+/// the implicit feature `name`.
+#[derive(Debug, Default)]
+pub struct Features {
+    // package directory => manifest
+    manifests: BTreeMap<PathBuf, Manifest>,
+    // package directory => {feature, ...}
+    enabled: BTreeMap<PathBuf, BTreeSet<String>>,
+    // package directory => {dependency name => directory}
+    deps: BTreeMap<PathBuf, BTreeMap<String, PathBuf>>,
+}
+
+impl Features {
+    pub fn resolve(root: &Path) -> Result<Self, BundleError> {
+        let root = root
+            .canonicalize()
+            .map_err(|e| BundleError::io("resolve", root, e))?;
+        let mut res = Self::default();
+        res.activate(&root, &[], true)?;
+        // `name?/feature` depends on whether `name` is activated by others,
+        // so we repeat until nothing changes.
+        while res.step()? {}
+        Ok(res)
+    }
+
+    /// Returns the activated path dependencies of the package in `dir`,
+    /// with `-` in their names replaced by `_`.
+    pub fn dependencies(&self, dir: &Path) -> BTreeMap<String, PathBuf> {
+        (self.deps.get(dir).into_iter().flatten())
+            .map(|(name, path)| (name.replace('-', "_"), path.clone()))
+            .collect()
+    }
+
+    /// Returns the manifests read in the resolution.
+    pub fn manifest_paths(&self) -> Vec<PathBuf> {
+        self.manifests.keys().map(|dir| dir.join("Cargo.toml")).collect()
+    }
+
+    /// Returns the enabled features of the package in `dir`.
+    pub fn enabled(&self, dir: &Path) -> BTreeSet<String> {
+        self.enabled.get(dir).cloned().unwrap_or_default()
+    }
+
+    fn step(&mut self) -> Result<bool, BundleError> {
+        let mut changed = false;
+        let dirs: Vec<_> = self.manifests.keys().cloned().collect();
+        for dir in dirs {
+            let manifest = &self.manifests[&dir];
+            let required: Vec<_> = (manifest.dependencies.iter())
+                .filter(|(_, dep)| !dep.optional())
+                .map(|(name, _)| name.clone())
+                .collect();
+            let mut values = vec![];
+            for feature in &self.enabled[&dir] {
+                match manifest.features.get(feature) {
+                    Some(v) => values.extend(v.iter().cloned()),
+                    // implicit feature of an optional dependency
+                    None => values.push(format!("dep:{feature}")),
+                }
+            }
+
+            for name in required {
+                changed |= self.activate_dep(&dir, &name)?;
+            }
+            for value in values {
+                if let Some(name) = value.strip_prefix("dep:") {
+                    changed |= self.activate_dep(&dir, name)?;
+                } else if let Some((name, feature)) = value.split_once('/') {
+                    let dep = match name.strip_suffix('?') {
+                        Some(name) => self.deps[&dir].get(name).cloned(),
+                        None => {
+                            changed |= self.enable(&dir, name);
+                            changed |= self.activate_dep(&dir, name)?;
+                            self.deps[&dir].get(name).cloned()
+                        }
+                    };
+                    if let Some(dep) = dep {
+                        changed |= self.enable(&dep, feature);
+                    }
+                } else {
+                    changed |= self.enable(&dir, &value);
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    fn activate(
+        &mut self,
+        dir: &Path,
+        features: &[String],
+        default_features: bool,
+    ) -> Result<bool, BundleError> {
+        if !self.manifests.contains_key(dir) {
+            let path = dir.join("Cargo.toml");
+            let manifest = Manifest::from_path(&path).map_err(|source| {
+                BundleError::ManifestParse { path, source }
+            })?;
+            self.manifests.insert(dir.to_owned(), manifest);
+            self.enabled.insert(dir.to_owned(), BTreeSet::new());
+            self.deps.insert(dir.to_owned(), BTreeMap::new());
+        }
+        let mut changed = false;
+        if default_features {
+            changed |= self.enable(dir, "default");
+        }
+        for feature in features {
+            changed |= self.enable(dir, feature);
+        }
+        Ok(changed)
+    }
+
+    fn activate_dep(
+        &mut self,
+        dir: &Path,
+        name: &str,
+    ) -> Result<bool, BundleError> {
+        let Some(detail) = self.manifests[dir]
+            .dependencies
+            .get(name)
+            .and_then(|dep| dep.detail())
+            .filter(|d| d.path.is_some())
+            .cloned()
+        else {
+            return Ok(false);
+        };
+        let path = dir.join(detail.path.unwrap());
+        let path = path
+            .canonicalize()
+            .map_err(|e| BundleError::io("resolve", &path, e))?;
+        let deps = self.deps.get_mut(dir).unwrap();
+        let changed = deps.insert(name.to_owned(), path.clone()).is_none();
+        Ok(self.activate(&path, &detail.features, detail.default_features)?
+            || changed)
+    }
+
+    /// Enables `feature` if the package in `dir` has it.
+    fn enable(&mut self, dir: &Path, feature: &str) -> bool {
+        let manifest = &self.manifests[dir];
+        let explicit = |name: &str| {
+            manifest
+                .features
+                .values()
+                .flatten()
+                .any(|v| v.strip_prefix("dep:").is_some_and(|v| v == name))
+        };
+        let defined = manifest.features.contains_key(feature)
+            || manifest
+                .dependencies
+                .get(feature)
+                .is_some_and(|dep| dep.optional() && !explicit(feature));
+        defined && self.enabled.get_mut(dir).unwrap().insert(feature.to_owned())
+    }
+}
+
+#[test]
+fn resolution() {
+    let dir = crate::temp::TempDir::new("features").unwrap();
+    let dir = dir.path();
+    let manifests = [
+        ("root", r#"a = { path = "../a" }"#, r#"default = ["a/x", "a?/z"]"#),
+        (
+            "a",
+            r#"b = { path = "../b", default-features = false }
+c = { path = "../c", optional = true }
+d = { path = "../d", optional = true, features = ["y"] }"#,
+            r#"default = []
+x = ["dep:d", "b?/x", "c?/w"]
+z = []"#,
+        ),
+        (
+            "b",
+            "",
+            r#"default = ["x"]
+x = []"#,
+        ),
+        ("c", "", "w = []"),
+        ("d", "", "y = []"),
+    ];
+    for (name, deps, features) in manifests {
+        let path = dir.join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        let manifest = format!(
+            r#"[package]
+name = "{name}"
+version = "0.1.0"
+
+[dependencies]
+{deps}
+
+[features]
+{features}
+"#
+        );
+        std::fs::write(path.join("Cargo.toml"), manifest).unwrap();
+    }
+
+    let res = Features::resolve(&dir.join("root")).unwrap();
+    let enabled = |name: &str| -> Vec<_> {
+        res.enabled(&dir.join(name)).into_iter().collect()
+    };
+    let deps = |name: &str| -> Vec<_> {
+        res.dependencies(&dir.join(name)).into_keys().collect()
+    };
+
+    assert_eq!(deps("a"), ["b", "d"]);
+    assert_eq!(enabled("a"), ["default", "x", "z"]);
+    assert_eq!(enabled("b"), ["x"]);
+    assert_eq!(enabled("d"), ["y"]);
+    assert!(!res.manifests.contains_key(&dir.join("c")));
+}
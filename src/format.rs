@@ -0,0 +1,238 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use proc_macro2::LineColumn;
+use quote::ToTokens;
+use syn::{Item, parse_file, spanned::Spanned};
+
+use crate::{edition::Edition, error::BundleError};
+
+const PLACEHOLDER: &str = "// nekolib-bundle: macro ";
+
+/// Formats `code` with `rustfmt`, keeping the layout of macro items.
+///
+/// Returns the formatted code along with the line of `code` each line of it
+/// comes from, if known.  Lines are 1-indexed.
+pub fn format(
+    code: &str,
+    rustfmt: &Path,
+    edition: Edition,
+) -> Result<(String, Vec<Option<usize>>), BundleError> {
+    let file =
+        parse_file(code).map_err(|e| BundleError::source_parse(None, e))?;
+
+    // `rustfmt` would reformat `macro_rules!` bodies, so we hide them.
+    let mut macros = vec![];
+    let mut items = BTreeMap::new();
+    collect_items(&file.items, &mut vec![], &mut macros, &mut items);
+
+    let lines: Vec<_> = code.split('\n').collect();
+    let mut hidden = "".to_owned();
+    let mut it = macros.iter().enumerate().peekable();
+    let mut i = 0;
+    while i < lines.len() {
+        if let Some((k, (start, end))) =
+            it.next_if(|(_, (s, _))| s.line == i + 1)
+        {
+            hidden += &lines[i][..byte_column(lines[i], start.column)];
+            hidden += &format!("{PLACEHOLDER}{k}\n");
+            let last = lines[end.line - 1];
+            let rest = &last[byte_column(last, end.column)..];
+            if !rest.trim().is_empty() {
+                hidden += rest;
+                hidden += "\n";
+            }
+            i = end.line;
+            continue;
+        }
+        hidden += lines[i];
+        hidden += "\n";
+        i += 1;
+    }
+
+    let formatted = run_rustfmt(&hidden, rustfmt, edition)?;
+
+    // match the items before and after formatting
+    let formatted_file = parse_file(&formatted)
+        .map_err(|e| BundleError::source_parse(None, e))?;
+    let mut formatted_items = BTreeMap::new();
+    collect_items(
+        &formatted_file.items,
+        &mut vec![],
+        &mut vec![],
+        &mut formatted_items,
+    );
+    let mut origin = vec![None; formatted.split('\n').count()];
+    for (key, spans) in formatted_items {
+        let Some(orig) = items.get_mut(&key) else { continue };
+        for (start, end) in spans {
+            let Some((orig_start, orig_end)) = orig.pop_front() else { break };
+            for line in start..=end {
+                origin[line - 1] =
+                    Some((orig_start + line - start).min(orig_end));
+            }
+        }
+    }
+
+    // restore the macros
+    let mut res = "".to_owned();
+    let mut res_lines = vec![];
+    for (line, orig) in formatted.lines().zip(origin) {
+        let trimmed = line.trim_start();
+        let Some(k) = trimmed.strip_prefix(PLACEHOLDER) else {
+            res += line;
+            res += "\n";
+            res_lines.push(orig);
+            continue;
+        };
+        // the placeholders may have been rewritten by `rustfmt`
+        let (start, end) = k
+            .parse::<usize>()
+            .ok()
+            .and_then(|k| macros.get(k).copied())
+            .ok_or_else(|| BundleError::Rustfmt {
+                message: format!(
+                    "{}: placeholder broken: `{trimmed}`",
+                    rustfmt.display()
+                ),
+            })?;
+        let indent = &line[..line.len() - trimmed.len()];
+        let text: Vec<_> = (start.line..=end.line)
+            .map(|i| {
+                let line = lines[i - 1];
+                if i == end.line {
+                    &line[..byte_column(line, end.column)]
+                } else {
+                    line
+                }
+            })
+            .collect();
+        let text_indent = text[1..]
+            .iter()
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.len() - s.trim_start().len())
+            .min()
+            .unwrap_or(0);
+        for (i, s) in text.iter().enumerate() {
+            let s = if i == 0 {
+                &s[byte_column(s, start.column)..]
+            } else {
+                &s[text_indent.min(s.len())..]
+            };
+            if !s.is_empty() {
+                res += indent;
+            }
+            res += s;
+            res += "\n";
+            res_lines.push(Some(start.line + i));
+        }
+    }
+    Ok((res, res_lines))
+}
+
+/// Converts a column in characters to one in bytes.
+fn byte_column(line: &str, column: usize) -> usize {
+    line.char_indices().nth(column).map_or(line.len(), |(i, _)| i)
+}
+
+// {(module path, tokens) => [(start line, end line)]}
+type Items = BTreeMap<(Vec<String>, String), VecDeque<(usize, usize)>>;
+
+fn collect_items(
+    items: &[Item],
+    path: &mut Vec<String>,
+    macros: &mut Vec<(LineColumn, LineColumn)>,
+    res: &mut Items,
+) {
+    for item in items {
+        let (start, end) = (item.span().start(), item.span().end());
+        let key = match item {
+            Item::Macro(_) => {
+                macros.push((start, end));
+                continue;
+            }
+            Item::Mod(item_mod) if item_mod.content.is_some() => {
+                path.push(item_mod.ident.to_string());
+                let (_, inner) = item_mod.content.as_ref().unwrap();
+                collect_items(inner, path, macros, res);
+                path.pop();
+                // only the first and the last lines
+                let key = (path.clone(), format!("mod {}", item_mod.ident));
+                let lines = res.entry(key).or_default();
+                lines.push_back((start.line, start.line));
+                let key = (path.clone(), format!("}} {}", item_mod.ident));
+                res.entry(key).or_default().push_back((end.line, end.line));
+                continue;
+            }
+            // `rustfmt` may add trailing commas
+            _ => {
+                let tokens = item.to_token_stream().to_string();
+                (path.clone(), tokens.replace(" ,", ""))
+            }
+        };
+        res.entry(key).or_default().push_back((start.line, end.line));
+    }
+}
+
+fn run_rustfmt(
+    code: &str,
+    rustfmt: &Path,
+    edition: Edition,
+) -> Result<String, BundleError> {
+    let error = |message: String| BundleError::Rustfmt {
+        message: format!("{}: {message}", rustfmt.display()),
+    };
+    let mut child = Command::new(rustfmt)
+        .arg("--edition")
+        .arg(edition.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| error(e.to_string()))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(code.as_bytes())
+        .map_err(|e| error(e.to_string()))?;
+    let output = child.wait_with_output().map_err(|e| error(e.to_string()))?;
+    if !output.status.success() {
+        return Err(error(String::from_utf8_lossy(&output.stderr).into()));
+    }
+    String::from_utf8(output.stdout).map_err(|e| error(e.to_string()))
+}
+
+#[test]
+fn macro_layout() {
+    let code = r#"pub mod a {
+pub fn f (x : u32) -> u32 { x + 1 }
+macro_rules! m {
+                ( $x:expr ) => {
+                    $x  +  1
+                };
+            }
+}
+"#;
+    // fails rather than passes when rustfmt is missing
+    let rustfmt = Path::new("rustfmt");
+    let (formatted, lines) = format(code, rustfmt, Edition::E2021).unwrap();
+    let expected = r#"pub mod a {
+    pub fn f(x: u32) -> u32 {
+        x + 1
+    }
+    macro_rules! m {
+        ( $x:expr ) => {
+            $x  +  1
+        };
+    }
+}
+"#;
+    assert_eq!(formatted, expected);
+    let expected = [1, 2, 2, 2, 3, 4, 5, 6, 7, 8];
+    assert_eq!(lines, expected.map(Some));
+}
@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+/// Output formats of the dependency graph.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    Json,
+    /// Indented tree, as `cargo tree`
+    #[default]
+    Tree,
+}
+
+/// The dependency graph of library crates, along with the reason each crate
+/// is included.
+#[derive(Debug, Serialize)]
+pub struct Graph {
+    /// The root name of the library, as `nekolib`.
+    #[serde(skip)]
+    pub name: String,
+    pub crates: Vec<Node>,
+    /// `[from, to]`, where `from` depends on `to`.
+    pub edges: Vec<[String; 2]>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Node {
+    #[serde(rename = "crate")]
+    pub cr: String,
+    pub reasons: Vec<Reason>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Reason {
+    /// Used by the source as `path`, e.g. `nekolib::ds::UnionFind`.
+    Use { path: String },
+    /// Depended on by `from`.
+    Dependency { from: String },
+}
+
+impl Graph {
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Json => {
+                serde_json::to_string_pretty(self).unwrap() + "\n"
+            }
+            GraphFormat::Tree => self.to_tree(),
+        }
+    }
+
+    fn to_dot(&self) -> String {
+        let mut res = format!("digraph {} {{\n", self.name);
+        for node in &self.crates {
+            res += &format!("    {:?};\n", node.cr);
+            for reason in &node.reasons {
+                if let Reason::Use { path } = reason {
+                    let edge = format!("{:?} -> {:?}", "<source>", node.cr);
+                    res += &format!("    {edge} [label={path:?}];\n");
+                }
+            }
+        }
+        for [from, to] in &self.edges {
+            res += &format!("    {from:?} -> {to:?};\n");
+        }
+        res + "}\n"
+    }
+
+    /// Crates used by the source, or crates with no dependents, are at the
+    /// roots.  Crates shown before are marked with `(*)`, and their
+    /// dependencies are omitted.
+    fn to_tree(&self) -> String {
+        let mut children = BTreeMap::<_, Vec<_>>::new();
+        for [from, to] in &self.edges {
+            children.entry(from.as_str()).or_default().push(to.as_str());
+        }
+
+        let is_root = |node: &Node| {
+            node.reasons.is_empty()
+                || node.reasons.iter().any(|r| matches!(r, Reason::Use { .. }))
+        };
+        // crates in a cycle may have no roots above them
+        let roots = (self.crates.iter().filter(|node| is_root(node)))
+            .chain(self.crates.iter().filter(|node| !is_root(node)));
+
+        let mut res = "".to_owned();
+        let mut shown = BTreeSet::new();
+        for node in roots {
+            if !is_root(node) && shown.contains(node.cr.as_str()) {
+                continue;
+            }
+            let uses: Vec<_> = (node.reasons.iter())
+                .filter_map(|r| match r {
+                    Reason::Use { path } => Some(path.as_str()),
+                    Reason::Dependency { .. } => None,
+                })
+                .collect();
+            res += &node.cr;
+            if !uses.is_empty() {
+                res += &format!(" ({})", uses.join(", "));
+            }
+            if shown.insert(node.cr.as_str()) {
+                res += "\n";
+                write_subtree(&node.cr, 1, &children, &mut shown, &mut res);
+            } else {
+                res += " (*)\n";
+            }
+        }
+        res
+    }
+}
+
+fn write_subtree<'a>(
+    cr: &str,
+    depth: usize,
+    children: &BTreeMap<&str, Vec<&'a str>>,
+    shown: &mut BTreeSet<&'a str>,
+    res: &mut String,
+) {
+    for &child in children.get(cr).into_iter().flatten() {
+        *res += &format!("{:1$}{child}", "", 4 * depth);
+        if shown.insert(child) {
+            *res += "\n";
+            write_subtree(child, depth + 1, children, shown, res);
+        } else {
+            *res += " (*)\n";
+        }
+    }
+}
+
+#[test]
+fn tree() {
+    let node = |cr: &str, reasons: &[Reason]| Node {
+        cr: cr.to_owned(),
+        reasons: reasons.to_vec(),
+    };
+    let dep = |from: &str| Reason::Dependency { from: from.to_owned() };
+    let graph = Graph {
+        name: "nekolib".to_owned(),
+        crates: vec![
+            node("ds::a", &[Reason::Use { path: "nekolib::ds::A".to_owned() }]),
+            node("ds::b", &[dep("ds::a"), dep("ds::c")]),
+            node("ds::c", &[]),
+            node("ds::d", &[dep("ds::b")]),
+        ],
+        edges: [["ds::a", "ds::b"], ["ds::b", "ds::d"], ["ds::c", "ds::b"]]
+            .map(|e| e.map(str::to_owned))
+            .to_vec(),
+    };
+    let expected = "\
+ds::a (nekolib::ds::A)
+    ds::b
+        ds::d
+ds::c
+    ds::b (*)
+";
+    assert_eq!(graph.render(GraphFormat::Tree), expected);
+}
+
+#[test]
+fn dot_name() {
+    let graph =
+        Graph { name: "snippets".to_owned(), crates: vec![], edges: vec![] };
+    assert_eq!(graph.render(GraphFormat::Dot), "digraph snippets {\n}\n");
+}
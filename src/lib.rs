@@ -1,5 +1,21 @@
+pub mod cache;
+pub mod cfg;
+pub mod check;
+pub mod edition;
+pub mod error;
+pub mod features;
+pub mod format;
+pub mod graph;
 pub mod library;
+pub mod macro_deps;
 pub mod metadata;
+pub mod minify;
 pub mod polish;
 pub mod resolve;
+pub mod search;
+pub mod shake;
 pub mod source;
+pub mod sourcemap;
+pub mod suggest;
+pub mod temp;
+pub mod watch;
@@ -0,0 +1,170 @@
+use proc_macro2::{Span, TokenStream, TokenTree};
+use serde::{Deserialize, Serialize};
+use syn::{Item, parse_file};
+
+use crate::{cfg::Cfg, library::Crate};
+
+/// A reference to another crate found in the body of a macro.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct MacroDep {
+    pub from: Crate,
+    pub to: Crate,
+    /// The reference as written, as `["fw"]` for `fw!`, `["$crate", "fw"]`
+    /// for `$crate::fw` and `["gcd", "gcd"]` for `gcd::gcd`.
+    pub path: Vec<String>,
+    /// Whether `to` is a dependency in `Cargo.toml`.
+    pub declared: bool,
+}
+
+impl std::fmt::Display for MacroDep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let by = match &self.path[..] {
+            [name] => format!("{name}!"),
+            path => path.join("::"),
+        };
+        write!(
+            f,
+            "a macro in {} uses `{by}` of {}, which is not a dependency in Cargo.toml",
+            self.from, self.to
+        )
+    }
+}
+
+/// What the bodies of `macro_rules!` in a file refer to.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct MacroRefs {
+    /// The references, in the form of `MacroDep::path`.
+    pub(crate) refs: Vec<Vec<String>>,
+    /// The names of the macros defined in the file.
+    pub(crate) defined: Vec<String>,
+}
+
+/// Scans the `macro_rules!` in `src`, including the ones in inline modules,
+/// skipping the items disabled by `cfg`.
+pub(crate) fn macro_refs(
+    src: &str,
+    cfg: &Cfg,
+) -> Result<MacroRefs, syn::Error> {
+    fn dfs(items: &[Item], cfg: &Cfg, res: &mut MacroRefs) {
+        for item in items {
+            match item {
+                Item::Macro(item) if !cfg.is_disabled(&item.attrs) => {
+                    let Some(ident) = &item.ident else { continue };
+                    res.defined.push(ident.to_string());
+                    visit_refs(item.mac.tokens.clone(), &mut |path, _| {
+                        res.refs.push(path)
+                    });
+                }
+                Item::Mod(item) if !cfg.is_disabled(&item.attrs) => {
+                    if let Some((_, items)) = &item.content {
+                        dfs(items, cfg, res);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let ast = parse_file(src)?;
+    let mut res = MacroRefs::default();
+    dfs(&ast.items, cfg, &mut res);
+    res.refs.sort_unstable();
+    res.refs.dedup();
+    res.defined.sort_unstable();
+    res.defined.dedup();
+    Ok(res)
+}
+
+/// Calls `f` for each reference that may point to another crate, with the
+/// span of the tokens to be replaced by the path to the crate: `$crate` of
+/// `$crate::name`, `head` of `head::name`, and `name` of `name!`.
+///
+/// Paths following `::` or a metavariable, as `$x::name`, are not
+/// references by themselves.
+pub(crate) fn visit_refs(
+    ts: TokenStream,
+    f: &mut impl FnMut(Vec<String>, [Span; 2]),
+) {
+    let tts: Vec<_> = ts.into_iter().collect();
+    let punct = |i: usize, c: char| match tts.get(i) {
+        Some(TokenTree::Punct(p)) => p.as_char() == c,
+        _ => false,
+    };
+    let ident = |i: usize| match tts.get(i) {
+        Some(TokenTree::Ident(ident)) => Some(ident.to_string()),
+        _ => None,
+    };
+    let path_sep = |i: usize| punct(i, ':') && punct(i + 1, ':');
+
+    for (i, tt) in tts.iter().enumerate() {
+        let head = match tt {
+            TokenTree::Group(group) => {
+                visit_refs(group.stream(), f);
+                continue;
+            }
+            TokenTree::Ident(ident) => ident.to_string(),
+            _ => continue,
+        };
+        if i >= 1 && punct(i - 1, ':') {
+            continue;
+        }
+        let dollar = i >= 1 && punct(i - 1, '$');
+        let span = [tt.span(), tt.span()];
+        match (dollar, ident(i + 3)) {
+            (true, Some(name)) if head == "crate" && path_sep(i + 1) => {
+                let span = [tts[i - 1].span(), tt.span()];
+                f(vec!["$crate".to_owned(), name], span);
+            }
+            (true, _) => {}
+            (false, Some(name)) if path_sep(i + 1) => f(vec![head, name], span),
+            (false, _) => match tts.get(i + 1) {
+                // not `!=`
+                Some(TokenTree::Punct(p))
+                    if p.as_char() == '!'
+                        && p.spacing() == proc_macro2::Spacing::Alone =>
+                {
+                    f(vec![head], span);
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+#[test]
+fn references() {
+    let src = r#"
+#[macro_export]
+macro_rules! bit {
+    ($n:expr) => { $crate::fw!($n) };
+    ($n:expr, $x:expr) => {{
+        let g = gcd::gcd($x, $x);
+        assert!($x != $n && $x::y());
+        println!("{}", $crate::Fenwick::<u64>::new($n));
+    }};
+}
+fn not_a_macro() { gcd::lcm(1, 2); other!(); }
+mod inner {
+    macro_rules! sum {
+        ($($x:expr),*) => { fold::fold(0, &[$($x),*]) };
+    }
+}
+#[cfg(test)]
+mod tests {
+    macro_rules! check { () => { tester::run!() }; }
+}
+"#;
+    let res = macro_refs(src, &Cfg::default()).unwrap();
+    assert_eq!(
+        res.refs,
+        [
+            vec!["$crate", "Fenwick"],
+            vec!["$crate", "fw"],
+            vec!["assert"],
+            vec!["fold", "fold"],
+            vec!["gcd", "gcd"],
+            vec!["println"],
+        ]
+    );
+    assert_eq!(res.defined, ["bit", "sum"]);
+}
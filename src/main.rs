@@ -1,37 +1,550 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand, error::ErrorKind};
 use nekolib_bundle::{
-    library::{Library, LIB_PATH_DEFAULT},
+    cache,
+    cfg::Cfg,
+    check::{CheckOptions, check},
+    edition::Edition,
+    error::BundleError,
+    graph::GraphFormat,
+    library::{
+        BundleOptions, Bundled, LIB_DOCS_DEFAULT, LIB_PATH_DEFAULT,
+        LIBRARY_NAME, Library, bundle_all,
+    },
     metadata::Metadata,
     source::Source,
+    watch::Watcher,
 };
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about)]
+#[command(
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(short, long, help = format!("Library path [{LIB_PATH_DEFAULT}]"))]
+    lib_path: Option<PathBuf>,
+    #[arg(
+        long = "library",
+        value_name = "NAME=PATH",
+        value_parser = Library::parse_option,
+        help = "Also bundle the library at PATH, used as `NAME::...`"
+    )]
+    libraries: Vec<(String, PathBuf)>,
+    #[arg(long, help = "Drop library items unreachable from the source")]
+    tree_shake: bool,
+    #[arg(long, help = "Shorten private names and strip whitespace")]
+    minify: bool,
+    #[arg(long, conflicts_with = "minify", help = "Format the library part")]
+    format: bool,
+    #[arg(long, default_value = "rustfmt", help = "rustfmt used by --format")]
+    rustfmt: PathBuf,
+    #[arg(long, help = "Escape non-ASCII characters in library literals")]
+    escape_unicode: bool,
+    #[arg(long, help = "Check that the bundled code compiles")]
+    check: bool,
+    #[arg(long, default_value = "rustc", help = "rustc used by --check")]
+    rustc: PathBuf,
+    #[arg(long, value_enum, default_value_t, help = "Edition of the output")]
+    edition: Edition,
+    #[arg(
+        long = "cfg",
+        value_name = "SPEC",
+        value_parser = Cfg::parse_option,
+        help = "Set a cfg option, e.g. `unix` or `target_os=\"linux\"`"
+    )]
+    cfgs: Vec<(String, Option<String>)>,
+    #[arg(long, help = "Bundle crates in a dependency cycle together")]
+    allow_cycles: bool,
+    #[arg(long, help = "Index the library from scratch without the cache")]
+    no_cache: bool,
+    #[arg(long, help = "Parse only the library crates the source depends on")]
+    lazy: bool,
+    #[arg(long, help = "Target triple used by --check")]
+    target: Option<String>,
+    #[arg(long, help = "Write a JSON source map of the output to the path")]
+    source_map: Option<PathBuf>,
+    #[arg(short, long, help = "Write the output to the path")]
+    output: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with = "output",
+        help = "Write the output to `*.bundled.rs` next to the source"
+    )]
+    bundled: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["output", "bundled"],
+        help = "Overwrite the source with the output"
+    )]
+    in_place: bool,
+    #[arg(
+        long,
+        value_name = "OUTPUT",
+        conflicts_with_all = ["in_place", "output", "bundled"],
+        help = "Bundle into OUTPUT again whenever the source or the library \
+                is modified"
+    )]
+    watch: Option<PathBuf>,
+    /// The source, or `-` for the standard input
+    #[arg(required = true)]
+    source_path: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the dependency graph of the library crates
+    Deps(DepsArgs),
+    /// Look up the library items by name
+    Search(SearchArgs),
+    /// Recover the original source from the bundled code
+    Unbundle(UnbundleArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct DepsArgs {
+    #[arg(short, long, help = format!("Library path [{LIB_PATH_DEFAULT}]"))]
+    lib_path: Option<PathBuf>,
+    #[arg(long, default_value = LIBRARY_NAME, help = "Root name of the library")]
+    name: String,
+    #[arg(long, value_enum, default_value_t, help = "Output format")]
+    format: GraphFormat,
+    #[arg(long, help = "Index the library from scratch without the cache")]
+    no_cache: bool,
+    /// Show only the crates needed by the source
+    source_path: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct SearchArgs {
     #[arg(short, long, help = format!("Library path [{LIB_PATH_DEFAULT}]"))]
     lib_path: Option<PathBuf>,
+    #[arg(long, default_value = LIBRARY_NAME, help = "Root name of the library")]
+    name: String,
+    #[arg(short = 'n', long, default_value_t = 10, help = "Maximum results")]
+    limit: usize,
+    #[arg(long, help = "Index the library from scratch without the cache")]
+    no_cache: bool,
+    /// Name of the item, matched fuzzily
+    query: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct UnbundleArgs {
+    /// The bundled code
     source_path: PathBuf,
 }
 
-impl Args {
-    fn lib_path(&self) -> PathBuf {
-        self.lib_path.clone().unwrap_or_else(|| {
-            let home = home::home_dir().unwrap();
-            LIB_PATH_DEFAULT.replace("~", home.to_str().unwrap()).into()
-        })
+fn lib_path(lib_path: &Option<PathBuf>) -> PathBuf {
+    lib_path.clone().unwrap_or_else(|| {
+        let home = home::home_dir().unwrap();
+        LIB_PATH_DEFAULT.replace("~", home.to_str().unwrap()).into()
+    })
+}
+
+/// Returns the libraries given by `--library`, preceded by `nekolib` at
+/// `-l` unless it is also given by `--library`, along with the URL of their
+/// documentation, which is known only for `nekolib`.
+fn libraries(args: &Args) -> Vec<(String, PathBuf, Option<String>)> {
+    let docs = |name: &str| {
+        (name == LIBRARY_NAME).then(|| LIB_DOCS_DEFAULT.to_owned())
+    };
+    let mut res = vec![];
+    if args.libraries.iter().all(|(name, _)| name != LIBRARY_NAME) {
+        let path = lib_path(&args.lib_path);
+        res.push((LIBRARY_NAME.to_owned(), path, docs(LIBRARY_NAME)));
+    } else if args.lib_path.is_some() {
+        let message =
+            format!("`-l` conflicts with `--library {LIBRARY_NAME}=`");
+        Args::command().error(ErrorKind::ArgumentConflict, message).exit();
+    }
+    for (name, path) in &args.libraries {
+        if res.iter().any(|(other, ..)| other == name) {
+            let message = format!("library `{name}` is given more than once");
+            Args::command().error(ErrorKind::ArgumentConflict, message).exit();
+        }
+        res.push((name.clone(), path.clone(), docs(name)));
     }
+    res
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn traverse(
+    name: &str,
+    lib_path: &Path,
+    no_cache: bool,
+) -> Result<Library, BundleError> {
+    match cache::default_dir() {
+        Some(dir) if !no_cache => {
+            Library::traverse_cached(name, lib_path, &dir)
+        }
+        _ => Library::traverse(name, lib_path),
+    }
+}
+
+/// Reads the source at `path`, or from the standard input if it is `-`.
+fn read_source(path: &Path) -> Result<Source, BundleError> {
+    read_source_from(path, std::io::stdin())
+}
+
+fn read_source_from(
+    path: &Path,
+    stdin: impl std::io::Read,
+) -> Result<Source, BundleError> {
+    let content = if path == Path::new("-") {
+        std::io::read_to_string(stdin)
+            .map_err(|e| BundleError::io("read", Path::new("<stdin>"), e))?
+    } else {
+        std::fs::read_to_string(path)
+            .map_err(|e| BundleError::io("read", path, e))?
+    };
+    Ok(Source::new(content))
+}
+
+/// Returns where to write the output, or `None` for the standard output.
+fn output_path(args: &Args) -> Option<PathBuf> {
+    let source_path = args.source_path.as_ref().unwrap();
+    if args.in_place {
+        Some(source_path.clone())
+    } else if args.bundled {
+        // main.rs => main.bundled.rs
+        Some(source_path.with_extension("bundled.rs"))
+    } else {
+        args.output.clone()
+    }
+}
+
+/// Writes `contents` into a temporary file and renames it to `path`, so
+/// that readers never see a partially written one.
+///
+/// If `path` is a symlink, the file it points to is replaced instead.  The
+/// permissions of the existing file are kept.
+fn write_atomic(path: &Path, contents: &str) -> Result<(), BundleError> {
+    let io_error = |e| BundleError::io("write", path, e);
+    let target = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    let permissions = std::fs::metadata(&target).map(|m| m.permissions());
+
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let tmp =
+        target.with_file_name(format!(".{name}.{}.tmp", std::process::id()));
+    std::fs::write(&tmp, contents).map_err(io_error)?;
+    let res = match permissions {
+        Ok(permissions) => std::fs::set_permissions(&tmp, permissions),
+        Err(_) => Ok(()),
+    };
+    res.and_then(|()| std::fs::rename(&tmp, &target)).map_err(|e| {
+        let _ = std::fs::remove_file(&tmp);
+        io_error(e)
+    })
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
 
-    let source = Source::new(std::fs::read_to_string(&args.source_path)?);
-    let library = Library::traverse(&args.lib_path())?;
-    let metadata = Metadata::fetch(&args.lib_path())?;
+    let res = match &args.command {
+        Some(Command::Deps(args)) => deps(args),
+        Some(Command::Search(args)) => search(args),
+        Some(Command::Unbundle(args)) => unbundle(args),
+        None => run(&args),
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<(), BundleError> {
+    let source_path = args.source_path.as_ref().unwrap();
+    let needs_file = args.bundled || args.in_place || args.watch.is_some();
+    if source_path == Path::new("-") && needs_file {
+        let message = "`--bundled`, `--in-place` and `--watch` need a file \
+                       as the source, not `-`";
+        Args::command().error(ErrorKind::ArgumentConflict, message).exit();
+    }
+
+    // the modules bundled before are replaced
+    let (source, _) = read_source(source_path)?.split_bundled()?;
+    let libraries = load_libraries(args, &source)?;
+    if let Some(output) = &args.watch {
+        return watch(args, libraries, output);
+    }
+
+    let bundled = bundle(args, &libraries, &source)?;
+    match output_path(args) {
+        Some(path) => {
+            write_atomic(&path, &format!("{}\n", bundled.get_code()))?
+        }
+        None => println!("{}", bundled.get_code()),
+    }
+
+    Ok(())
+}
+
+fn load_libraries(
+    args: &Args,
+    source: &Source,
+) -> Result<Vec<(Library, Metadata)>, BundleError> {
+    let mut res = vec![];
+    for (name, lib_path, docs) in libraries(args) {
+        let library = if args.lazy {
+            Library::traverse_lazy(&name, &lib_path, source)?
+        } else {
+            traverse(&name, &lib_path, args.no_cache)?
+        };
+        let metadata = Metadata::fetch(&lib_path)?.with_docs(docs);
+        res.push((library, metadata));
+    }
+    Ok(res)
+}
+
+/// Bundles `source`, and then checks it and writes the source map if asked.
+fn bundle(
+    args: &Args,
+    libraries: &[(Library, Metadata)],
+    source: &Source,
+) -> Result<Bundled, BundleError> {
+    let options = BundleOptions {
+        tree_shake: args.tree_shake,
+        minify: args.minify,
+        format: args.format.then(|| args.rustfmt.clone()),
+        escape_unicode: args.escape_unicode,
+        edition: args.edition,
+        cfg: Cfg::new(args.cfgs.clone()),
+        allow_cycles: args.allow_cycles,
+    };
+
+    let bundled = bundle_all(libraries, source, &options)?;
+    for dep in bundled.undeclared_deps() {
+        eprintln!("warning: {dep}");
+    }
+    if let Some((before, after)) = bundled.minified_len() {
+        eprintln!("minified: {before} bytes -> {after} bytes");
+    }
+
+    if args.check {
+        let options = CheckOptions {
+            rustc: args.rustc.clone(),
+            edition: args.edition,
+            target: args.target.clone(),
+        };
+        check(&bundled, &options)?;
+    }
+
+    if let Some(path) = &args.source_map {
+        let source_path = args.source_path.as_deref().unwrap();
+        let source_path = if source_path == Path::new("-") {
+            Path::new("<stdin>")
+        } else {
+            source_path
+        };
+        let json = bundled.source_map(source_path).to_json()?;
+        std::fs::write(path, json)
+            .map_err(|e| BundleError::io("write", path, e))?;
+    }
+
+    Ok(bundled)
+}
+
+/// Bundles the source into `output` each time the source, or the library
+/// files it is bundled from, are modified.  Errors are reported and the
+/// files are watched again.
+fn watch(
+    args: &Args,
+    mut libraries: Vec<(Library, Metadata)>,
+    output: &Path,
+) -> Result<(), BundleError> {
+    let source_path = args.source_path.as_ref().unwrap();
+    let roots: Vec<_> = libraries.iter().map(|(l, _)| l.path()).collect();
+    check_watch_output(source_path, &roots, output)?;
+    let mut watcher = Watcher::new()?;
+    let mut files = BTreeSet::from([source_path.clone()]);
+    loop {
+        match bundle_into(args, &mut libraries, output) {
+            Ok(bundled) => {
+                eprintln!("bundled into {}", output.display());
+                files = BTreeSet::from([source_path.clone()]);
+                for (library, _) in &libraries {
+                    files.extend(library.manifest_paths().map(Path::to_owned));
+                }
+                files.extend(bundled.files().iter().cloned());
+            }
+            Err(e) => eprintln!("error: {e}"),
+        }
 
-    println!("{}", library.bundle(&source, &metadata));
+        watcher.watch(&files)?;
+        let changed = watcher.wait()?;
+        let source_changed = (source_path.canonicalize().ok())
+            .is_some_and(|path| changed.contains(&path));
+        // the categories to parse depend on the source
+        let res = if args.lazy && source_changed {
+            read_source(source_path)
+                .and_then(|source| Ok(source.split_bundled()?.0))
+                .and_then(|source| load_libraries(args, &source))
+                .map(|res| libraries = res)
+        } else {
+            (libraries.iter_mut())
+                .try_for_each(|(library, _)| library.refresh(&changed))
+        };
+        if let Err(e) = res {
+            eprintln!("error: {e}");
+        }
+    }
+}
 
+/// Fails if `output` is the source or inside a library, where writing it
+/// would be taken as a change and bundled again forever.
+fn check_watch_output(
+    source_path: &Path,
+    roots: &[&Path],
+    output: &Path,
+) -> Result<(), BundleError> {
+    let resolve = |path: &Path| {
+        path.canonicalize().map_err(|e| BundleError::io("resolve", path, e))
+    };
+    // `output` may not exist yet, while its directory has to.
+    let resolved = match (output.parent(), output.file_name()) {
+        (Some(dir), Some(name)) if !output.exists() => {
+            let dir =
+                if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            resolve(dir)?.join(name)
+        }
+        _ => resolve(output)?,
+    };
+    let mut watched = resolved == resolve(source_path)?;
+    for root in roots {
+        watched |= resolved.starts_with(resolve(root)?);
+    }
+    if watched {
+        return Err(BundleError::WatchedOutput { path: output.to_owned() });
+    }
     Ok(())
 }
+
+fn bundle_into(
+    args: &Args,
+    libraries: &mut [(Library, Metadata)],
+    output: &Path,
+) -> Result<Bundled, BundleError> {
+    let source_path = args.source_path.as_ref().unwrap();
+    let (source, _) = read_source(source_path)?.split_bundled()?;
+    // the library may have been committed since
+    for (library, metadata) in libraries.iter_mut() {
+        let docs = metadata.get_docs().map(str::to_owned);
+        *metadata = Metadata::fetch(library.path())?.with_docs(docs);
+    }
+    let bundled = bundle(args, libraries, &source)?;
+    write_atomic(output, &format!("{}\n", bundled.get_code()))?;
+    Ok(bundled)
+}
+
+fn deps(args: &DepsArgs) -> Result<(), BundleError> {
+    let library =
+        traverse(&args.name, &lib_path(&args.lib_path), args.no_cache)?;
+    let source = args.source_path.as_deref().map(read_source).transpose()?;
+    let graph = library.graph(source.as_ref())?;
+    print!("{}", graph.render(args.format));
+    Ok(())
+}
+
+fn search(args: &SearchArgs) -> Result<(), BundleError> {
+    let library =
+        traverse(&args.name, &lib_path(&args.lib_path), args.no_cache)?;
+    for hit in library.search(&args.query, args.limit)? {
+        println!("{hit}");
+    }
+    Ok(())
+}
+
+fn unbundle(args: &UnbundleArgs) -> Result<(), BundleError> {
+    let (source, headers) = read_source(&args.source_path)?.split_bundled()?;
+    if headers.is_empty() {
+        let path = args.source_path.clone();
+        return Err(BundleError::NotBundled { path });
+    }
+    for header in &headers {
+        eprintln!("{}: commit {}", header.name, header.commit);
+    }
+    print!("{}", source.get_code());
+    Ok(())
+}
+
+#[test]
+fn output_paths() {
+    let output = |args: &[&str]| {
+        let args = ["nekolib-bundle"].iter().chain(args);
+        output_path(&Args::try_parse_from(args).unwrap())
+    };
+    assert_eq!(output(&["main.rs"]), None);
+    assert_eq!(output(&["-o", "out.rs", "main.rs"]), Some("out.rs".into()));
+    assert_eq!(output(&["--in-place", "main.rs"]), Some("main.rs".into()));
+    assert_eq!(
+        output(&["--bundled", "src/main.rs"]),
+        Some("src/main.bundled.rs".into())
+    );
+    assert!(
+        Args::try_parse_from(["", "--in-place", "-o", "a.rs", "b.rs"]).is_err()
+    );
+}
+
+#[test]
+fn watched_output() {
+    let dir = nekolib_bundle::temp::TempDir::new("watched").unwrap();
+    let dir = dir.path();
+    let source = dir.join("main.rs");
+    let root = dir.join("nekolib-src");
+    std::fs::write(&source, "fn main() {}\n").unwrap();
+    std::fs::create_dir(&root).unwrap();
+
+    let check = |output: &Path| check_watch_output(&source, &[&root], output);
+    assert!(check(&dir.join("main.bundled.rs")).is_ok());
+    assert!(check(&root.join("../main.rs")).is_err());
+    assert!(check(&root.join("out.rs")).is_err());
+    assert!(check(&root).is_err());
+}
+
+#[test]
+fn source_from_stdin() {
+    let stdin = "fn main() {}\n".as_bytes();
+    let source = read_source_from(Path::new("-"), stdin).unwrap();
+    assert_eq!(source.get_code(), "fn main() {}\n");
+}
+
+#[test]
+fn atomic_writes() {
+    let dir = nekolib_bundle::temp::TempDir::new("write").unwrap();
+    let dir = dir.path();
+    let path = dir.join("out.rs");
+    write_atomic(&path, "a").unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "a");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = |path: &Path| {
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+        };
+        let permissions = std::fs::Permissions::from_mode(0o750);
+        std::fs::set_permissions(&path, permissions).unwrap();
+        let link = dir.join("link.rs");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        write_atomic(&link, "b").unwrap();
+        assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "b");
+        assert_eq!(mode(&path), 0o750);
+    }
+
+    let entries = std::fs::read_dir(dir).unwrap().count();
+    // no temporary file is left
+    assert_eq!(entries, if cfg!(unix) { 2 } else { 1 });
+}
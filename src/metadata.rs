@@ -3,36 +3,58 @@ use std::{
     process::{Command, Stdio},
 };
 
+use crate::error::BundleError;
+
 pub struct Metadata {
     commit: String,
+    // the URL of the documentation, mentioned in the bundled code
+    docs: Option<String>,
 }
 
 impl Metadata {
-    pub fn fetch(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn fetch(path: &Path) -> Result<Self, BundleError> {
         let commit = {
-            let child = Command::new("git")
-                .current_dir(path)
-                .args(["log", "-1", "--format=%H"])
-                .stdout(Stdio::piped())
-                .spawn()?;
-            let output = child.wait_with_output()?;
-            String::from_utf8_lossy(&output.stdout).to_string()
+            let stdout = git(path, &["log", "-1", "--format=%H"])?;
+            String::from_utf8_lossy(&stdout).to_string()
         };
 
         let dirty = {
-            let child = Command::new("git")
-                .current_dir(path)
-                .args(["status", "-s"])
-                .stdout(Stdio::piped())
-                .spawn()?;
-            let output = child.wait_with_output()?;
-            !output.stdout.is_empty()
+            let stdout = git(path, &["status", "-s"])?;
+            !stdout.is_empty()
         };
 
         let commit =
             commit.trim_end().to_owned() + if dirty { "-dirty" } else { "" };
-        Ok(Self { commit })
+        Ok(Self { commit, docs: None })
+    }
+
+    pub fn with_docs(self, docs: Option<String>) -> Self {
+        Self { docs, ..self }
     }
 
     pub fn get_commit(&self) -> &str { &self.commit }
+
+    pub fn get_docs(&self) -> Option<&str> { self.docs.as_deref() }
+
+    #[cfg(test)]
+    pub(crate) fn new(commit: &str) -> Self {
+        Self { commit: commit.to_owned(), docs: None }
+    }
+}
+
+fn git(path: &Path, args: &[&str]) -> Result<Vec<u8>, BundleError> {
+    let output = Command::new("git")
+        .current_dir(path)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|child| child.wait_with_output())
+        .map_err(|e| BundleError::Git { message: e.to_string() })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(BundleError::Git { message: stderr.trim_end().to_owned() });
+    }
+    Ok(output.stdout)
 }
@@ -0,0 +1,661 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use proc_macro2::{Delimiter, Group, Ident, Spacing, TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::{
+    Item, parse_file,
+    spanned::Spanned,
+    visit::{self, Visit},
+    visit_mut::{self, VisitMut},
+};
+
+use crate::{library::Crate, source::dfs_use_tree};
+
+pub struct Minified {
+    pub code: String,
+    /// The line in the input code each line of `code` comes from.
+    pub lines: Vec<usize>,
+}
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const",
+    "continue", "crate", "do", "dyn", "else", "enum", "extern", "false",
+    "final", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "macro",
+    "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "union", "unsafe", "unsized", "use", "virtual", "where", "while",
+    "yield",
+];
+
+/// Minifies the code of each crate.
+///
+/// Private top-level functions, constants and statics, and local variables
+/// in functions are renamed to short identifiers, unless their names could
+/// refer to something else.  Each top-level item is printed on its own line
+/// without redundant whitespace.  Identifiers in `user` code are never used
+/// as new names, and items named in it keep their names.
+pub fn minify(
+    user: &str,
+    crates: &BTreeMap<Crate, String>,
+) -> BTreeMap<Crate, Minified> {
+    let mut asts: Vec<_> = crates
+        .iter()
+        .map(|(cr, code)| (cr, parse_file(code).unwrap()))
+        .collect();
+
+    let user_idents = collect_idents(user.parse().unwrap());
+    let mut taken = user_idents.clone();
+    // {ident => the number of crates it appears in}
+    let mut appears = BTreeMap::<_, usize>::new();
+    for (_, ast) in &asts {
+        for ident in collect_idents(ast.to_token_stream()) {
+            taken.insert(ident.clone());
+            *appears.entry(ident).or_default() += 1;
+        }
+    }
+
+    let mut next = 0;
+    let mut res = BTreeMap::new();
+    for (cr, ast) in &mut asts {
+        let lines: Vec<_> =
+            ast.items.iter().map(|item| item.span().start().line).collect();
+
+        let mut defs = Defs::default();
+        defs.visit_file(ast);
+
+        let ts = ast.to_token_stream();
+        let mut renames = BTreeMap::new();
+        for item in &ast.items {
+            // constants keep being in upper case
+            let (ident, upper) = match item {
+                Item::Fn(item) if is_private(&item.vis) => {
+                    (&item.sig.ident, false)
+                }
+                Item::Const(item) if is_private(&item.vis) => {
+                    (&item.ident, true)
+                }
+                Item::Static(item) if is_private(&item.vis) => {
+                    (&item.ident, true)
+                }
+                _ => continue,
+            };
+            let name = ident.to_string();
+            if user_idents.contains(&name)
+                || appears[&name] > 1
+                || defs.nonlocal[&name] > 1
+                || defs.local.contains(&name)
+                || !only_plain_uses(ts.clone(), &name, &defs.mods)
+            {
+                continue;
+            }
+            let new = fresh(&mut next, &taken, upper);
+            taken.insert(new.clone());
+            renames.insert(name, new);
+        }
+        RenameItems { renames: &renames }.visit_file_mut(ast);
+
+        let mut rename_locals =
+            RenameLocals { nonlocal: &defs.nonlocal, taken: &taken };
+        rename_locals.visit_file_mut(ast);
+
+        let mut code = "".to_owned();
+        for item in &ast.items {
+            code += &print_tokens(item.to_token_stream());
+            code += "\n";
+        }
+        res.insert((*cr).clone(), Minified { code, lines });
+    }
+    res
+}
+
+fn is_private(vis: &syn::Visibility) -> bool {
+    matches!(vis, syn::Visibility::Inherited)
+}
+
+fn fresh(next: &mut usize, taken: &BTreeSet<String>, upper: bool) -> String {
+    let base = if upper { b'A' } else { b'a' };
+    loop {
+        // a, b, ..., z, aa, ab, ...
+        let mut n = *next;
+        *next += 1;
+        let mut name = vec![];
+        loop {
+            name.push(base + (n % 26) as u8);
+            if n < 26 {
+                break;
+            }
+            n = n / 26 - 1;
+        }
+        name.reverse();
+        let name = String::from_utf8(name).unwrap();
+        if !KEYWORDS.contains(&name.as_str()) && !taken.contains(&name) {
+            return name;
+        }
+    }
+}
+
+/// Identifiers defined in a crate.
+#[derive(Default)]
+struct Defs {
+    // items, fields, variants, associated items, generics, and so on
+    nonlocal: BTreeMap<String, usize>,
+    // bindings in patterns
+    local: BTreeSet<String>,
+    mods: BTreeSet<String>,
+}
+
+impl Defs {
+    fn def(&mut self, ident: &Ident) {
+        *self.nonlocal.entry(ident.to_string()).or_default() += 1;
+    }
+}
+
+impl<'ast> Visit<'ast> for Defs {
+    fn visit_item(&mut self, node: &'ast Item) {
+        match node {
+            Item::Const(item) => self.def(&item.ident),
+            Item::Enum(item) => self.def(&item.ident),
+            Item::ExternCrate(item) => {
+                self.def(item.rename.as_ref().map_or(&item.ident, |(_, i)| i))
+            }
+            Item::Macro(item) => {
+                if let Some(ident) = &item.ident {
+                    self.def(ident);
+                }
+            }
+            Item::Mod(item) => {
+                self.def(&item.ident);
+                self.mods.insert(item.ident.to_string());
+            }
+            Item::Static(item) => self.def(&item.ident),
+            Item::Struct(item) => self.def(&item.ident),
+            Item::Trait(item) => self.def(&item.ident),
+            Item::TraitAlias(item) => self.def(&item.ident),
+            Item::Type(item) => self.def(&item.ident),
+            Item::Union(item) => self.def(&item.ident),
+            Item::Use(item) => {
+                for u in dfs_use_tree(&item.tree, &mut vec![]) {
+                    let leaf = u.last().unwrap();
+                    *self.nonlocal.entry(leaf.clone()).or_default() += 1;
+                }
+            }
+            _ => {}
+        }
+        visit::visit_item(self, node);
+    }
+
+    fn visit_signature(&mut self, node: &'ast syn::Signature) {
+        self.def(&node.ident);
+        visit::visit_signature(self, node);
+    }
+
+    fn visit_impl_item_const(&mut self, node: &'ast syn::ImplItemConst) {
+        self.def(&node.ident);
+        visit::visit_impl_item_const(self, node);
+    }
+
+    fn visit_impl_item_type(&mut self, node: &'ast syn::ImplItemType) {
+        self.def(&node.ident);
+        visit::visit_impl_item_type(self, node);
+    }
+
+    fn visit_trait_item_const(&mut self, node: &'ast syn::TraitItemConst) {
+        self.def(&node.ident);
+        visit::visit_trait_item_const(self, node);
+    }
+
+    fn visit_trait_item_type(&mut self, node: &'ast syn::TraitItemType) {
+        self.def(&node.ident);
+        visit::visit_trait_item_type(self, node);
+    }
+
+    fn visit_field(&mut self, node: &'ast syn::Field) {
+        if let Some(ident) = &node.ident {
+            self.def(ident);
+        }
+        visit::visit_field(self, node);
+    }
+
+    fn visit_variant(&mut self, node: &'ast syn::Variant) {
+        self.def(&node.ident);
+        visit::visit_variant(self, node);
+    }
+
+    fn visit_type_param(&mut self, node: &'ast syn::TypeParam) {
+        self.def(&node.ident);
+        visit::visit_type_param(self, node);
+    }
+
+    fn visit_const_param(&mut self, node: &'ast syn::ConstParam) {
+        self.def(&node.ident);
+        visit::visit_const_param(self, node);
+    }
+
+    fn visit_pat_ident(&mut self, node: &'ast syn::PatIdent) {
+        self.local.insert(node.ident.to_string());
+        visit::visit_pat_ident(self, node);
+    }
+}
+
+/// Checks that every occurrence of `name` is a plain use of an item, such
+/// as `name(..)` or `self::name`, and not `x.name`, `name!` or `"{name}"`.
+fn only_plain_uses(
+    ts: TokenStream,
+    name: &str,
+    mods: &BTreeSet<String>,
+) -> bool {
+    let tts: Vec<_> = ts.into_iter().collect();
+    tts.iter().enumerate().all(|(i, tt)| match tt {
+        TokenTree::Group(group) => only_plain_uses(group.stream(), name, mods),
+        TokenTree::Literal(lit) => !in_format_string(&lit.to_string(), name),
+        TokenTree::Ident(ident) if ident == name => {
+            let prev = i.checked_sub(1).and_then(|i| punct(&tts, i));
+            if matches!(prev, Some('.' | '$' | '\''))
+                || punct(&tts, i + 1) == Some('!')
+            {
+                return false;
+            }
+            if prev == Some(':') {
+                // `self::name`, `super::name` or `inner::name`
+                return match i.checked_sub(3).map(|i| &tts[i]) {
+                    Some(TokenTree::Ident(ident)) => {
+                        ident == "self"
+                            || ident == "super"
+                            || mods.contains(&ident.to_string())
+                    }
+                    _ => false,
+                };
+            }
+            true
+        }
+        _ => true,
+    })
+}
+
+fn in_format_string(lit: &str, name: &str) -> bool {
+    lit.starts_with('"')
+        && [format!("{{{name}}}"), format!("{{{name}:"), format!("{name}$")]
+            .iter()
+            .any(|pat| lit.contains(pat.as_str()))
+}
+
+fn punct(tts: &[TokenTree], i: usize) -> Option<char> {
+    match tts.get(i) {
+        Some(TokenTree::Punct(p)) => Some(p.as_char()),
+        _ => None,
+    }
+}
+
+struct RenameItems<'a> {
+    renames: &'a BTreeMap<String, String>,
+}
+
+impl VisitMut for RenameItems<'_> {
+    fn visit_ident_mut(&mut self, node: &mut Ident) {
+        if let Some(new) = self.renames.get(&node.to_string()) {
+            *node = Ident::new(new, node.span());
+        }
+    }
+
+    fn visit_macro_mut(&mut self, node: &mut syn::Macro) {
+        visit_mut::visit_macro_mut(self, node);
+        node.tokens = rename_tokens(node.tokens.clone(), self.renames, true);
+    }
+}
+
+/// Renames the local variables of each function.
+struct RenameLocals<'a> {
+    nonlocal: &'a BTreeMap<String, usize>,
+    taken: &'a BTreeSet<String>,
+}
+
+impl RenameLocals<'_> {
+    fn renames(&self, locals: Locals) -> BTreeMap<String, String> {
+        let mut next = 0;
+        locals
+            .bindings
+            .into_iter()
+            .filter(|name| {
+                name.len() > 1
+                    && name != "self"
+                    && !name.starts_with(|c: char| c.is_ascii_uppercase())
+                    && !self.nonlocal.contains_key(name)
+                    && !locals.excluded.contains(name)
+            })
+            .map(|name| (name, fresh(&mut next, self.taken, false)))
+            .collect()
+    }
+}
+
+impl VisitMut for RenameLocals<'_> {
+    fn visit_item_fn_mut(&mut self, node: &mut syn::ItemFn) {
+        let mut locals = Locals::default();
+        locals.visit_item_fn(node);
+        let renames = self.renames(locals);
+        RenameIn { renames: &renames }.visit_item_fn_mut(node);
+    }
+
+    fn visit_impl_item_fn_mut(&mut self, node: &mut syn::ImplItemFn) {
+        let mut locals = Locals::default();
+        locals.visit_impl_item_fn(node);
+        let renames = self.renames(locals);
+        RenameIn { renames: &renames }.visit_impl_item_fn_mut(node);
+    }
+
+    fn visit_trait_item_fn_mut(&mut self, node: &mut syn::TraitItemFn) {
+        let mut locals = Locals::default();
+        locals.visit_trait_item_fn(node);
+        let renames = self.renames(locals);
+        RenameIn { renames: &renames }.visit_trait_item_fn_mut(node);
+    }
+}
+
+/// Bindings in a function, and names that cannot be renamed because they
+/// are also used as field names, e.g. `Foo { x }` or `"{x}"`.
+#[derive(Default)]
+struct Locals {
+    bindings: BTreeSet<String>,
+    excluded: BTreeSet<String>,
+}
+
+impl<'ast> Visit<'ast> for Locals {
+    fn visit_pat_ident(&mut self, node: &'ast syn::PatIdent) {
+        self.bindings.insert(node.ident.to_string());
+        visit::visit_pat_ident(self, node);
+    }
+
+    fn visit_field_value(&mut self, node: &'ast syn::FieldValue) {
+        if let (syn::Member::Named(ident), None) =
+            (&node.member, node.colon_token)
+        {
+            self.excluded.insert(ident.to_string());
+        }
+        visit::visit_field_value(self, node);
+    }
+
+    fn visit_field_pat(&mut self, node: &'ast syn::FieldPat) {
+        if let (syn::Member::Named(ident), None) =
+            (&node.member, node.colon_token)
+        {
+            self.excluded.insert(ident.to_string());
+        }
+        visit::visit_field_pat(self, node);
+    }
+
+    fn visit_macro(&mut self, node: &'ast syn::Macro) {
+        exclude_in_macro(node.tokens.clone(), &mut self.excluded);
+        visit::visit_macro(self, node);
+    }
+}
+
+/// Finds identifiers in macro arguments that may not be plain variables:
+/// `x: ..` and `x = ..` (struct fields or named arguments), `{ x }`
+/// (shorthand fields) and `"{x}"` (captured arguments).
+fn exclude_in_macro(ts: TokenStream, excluded: &mut BTreeSet<String>) {
+    let tts: Vec<_> = ts.into_iter().collect();
+    for (i, tt) in tts.iter().enumerate() {
+        match tt {
+            TokenTree::Group(group) => {
+                let inner: Vec<_> = group.stream().into_iter().collect();
+                if group.delimiter() == Delimiter::Brace {
+                    for (j, tt) in inner.iter().enumerate() {
+                        let TokenTree::Ident(ident) = tt else { continue };
+                        // `{ x }`, `{ x, .. }` or `{ .., x }`
+                        let after_sep =
+                            j == 0 || punct(&inner, j - 1) == Some(',');
+                        let before_sep = j + 1 == inner.len()
+                            || punct(&inner, j + 1) == Some(',');
+                        if after_sep && before_sep {
+                            excluded.insert(ident.to_string());
+                        }
+                    }
+                }
+                exclude_in_macro(group.stream(), excluded);
+            }
+            TokenTree::Ident(ident) => {
+                let next = punct(&tts, i + 1);
+                let next2 = punct(&tts, i + 2);
+                if matches!((next, next2), (Some(':'), n) if n != Some(':'))
+                    || matches!((next, next2), (Some('='), n) if n != Some('='))
+                {
+                    excluded.insert(ident.to_string());
+                }
+            }
+            TokenTree::Literal(lit) => {
+                let lit = lit.to_string();
+                if lit.starts_with('"') {
+                    excluded.extend(format_names(&lit));
+                }
+            }
+            TokenTree::Punct(_) => {}
+        }
+    }
+}
+
+/// Names captured in a format string, e.g. `x` and `w` in `"{x:w$}"`.
+fn format_names(lit: &str) -> Vec<String> {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut res = vec![];
+    let mut rest = lit;
+    while let Some(i) = rest.find('{') {
+        rest = &rest[i + 1..];
+        let end = rest.find('}').unwrap_or(rest.len());
+        for name in rest[..end].split(|c| !is_ident(c)) {
+            if !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+            {
+                res.push(name.to_owned());
+            }
+        }
+    }
+    res
+}
+
+/// Renames the bindings of a function and the variables referring to them.
+struct RenameIn<'a> {
+    renames: &'a BTreeMap<String, String>,
+}
+
+impl VisitMut for RenameIn<'_> {
+    fn visit_pat_ident_mut(&mut self, node: &mut syn::PatIdent) {
+        if let Some(new) = self.renames.get(&node.ident.to_string()) {
+            node.ident = Ident::new(new, node.ident.span());
+        }
+        visit_mut::visit_pat_ident_mut(self, node);
+    }
+
+    fn visit_expr_path_mut(&mut self, node: &mut syn::ExprPath) {
+        if node.qself.is_none() && node.path.leading_colon.is_none() {
+            if let Some(ident) = node.path.get_ident() {
+                if let Some(new) = self.renames.get(&ident.to_string()) {
+                    let new = Ident::new(new, ident.span());
+                    node.path.segments[0].ident = new;
+                }
+            }
+        }
+        visit_mut::visit_expr_path_mut(self, node);
+    }
+
+    fn visit_macro_mut(&mut self, node: &mut syn::Macro) {
+        visit_mut::visit_macro_mut(self, node);
+        node.tokens = rename_tokens(node.tokens.clone(), self.renames, false);
+    }
+}
+
+/// Renames identifiers in macro arguments or bodies, except for the ones
+/// that are obviously not variables or items, e.g. `x.name` or `name!`.
+/// Identifiers after `::` are renamed only if `path` is set.
+fn rename_tokens(
+    ts: TokenStream,
+    renames: &BTreeMap<String, String>,
+    path: bool,
+) -> TokenStream {
+    let tts: Vec<_> = ts.into_iter().collect();
+    let mut res = vec![];
+    for (i, tt) in tts.iter().enumerate() {
+        match tt {
+            TokenTree::Group(group) => {
+                let stream = rename_tokens(group.stream(), renames, path);
+                let mut new = Group::new(group.delimiter(), stream);
+                new.set_span(group.span());
+                res.push(new.into());
+            }
+            TokenTree::Ident(ident) => {
+                let prev = i.checked_sub(1).and_then(|i| punct(&tts, i));
+                let next = punct(&tts, i + 1);
+                let skip = matches!(prev, Some('.' | '$' | '\''))
+                    || (prev == Some(':') && !path)
+                    || next == Some('!')
+                    || (next == Some(':') && punct(&tts, i + 2) == Some(':'));
+                match renames.get(&ident.to_string()) {
+                    Some(new) if !skip => {
+                        res.push(Ident::new(new, ident.span()).into())
+                    }
+                    _ => res.push(tt.clone()),
+                }
+            }
+            _ => res.push(tt.clone()),
+        }
+    }
+    res.into_iter().collect()
+}
+
+fn collect_idents(ts: TokenStream) -> BTreeSet<String> {
+    let mut res = BTreeSet::new();
+    for tt in ts {
+        match tt {
+            TokenTree::Group(group) => {
+                res.extend(collect_idents(group.stream()))
+            }
+            TokenTree::Ident(ident) => {
+                res.insert(ident.to_string());
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+/// Prints tokens with as few spaces as possible.
+pub fn print_tokens(ts: TokenStream) -> String {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Last {
+        None,
+        // identifiers and literals
+        Word,
+        Literal,
+        // a punctuation not joined with the next one
+        AlonePunct(char),
+        Other,
+    }
+
+    fn print(ts: TokenStream, res: &mut String, last: &mut Last) {
+        for tt in ts {
+            match tt {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    *res += open;
+                    if !open.is_empty() {
+                        *last = Last::Other;
+                    }
+                    print(group.stream(), res, last);
+                    *res += close;
+                    if !close.is_empty() {
+                        *last = Last::Other;
+                    }
+                }
+                TokenTree::Ident(ident) => {
+                    if matches!(*last, Last::Word | Last::Literal) {
+                        *res += " ";
+                    }
+                    *res += &ident.to_string();
+                    *last = Last::Word;
+                }
+                TokenTree::Literal(lit) => {
+                    if matches!(*last, Last::Word | Last::Literal) {
+                        *res += " ";
+                    }
+                    *res += &lit.to_string();
+                    *last = Last::Literal;
+                }
+                TokenTree::Punct(punct) => {
+                    let c = punct.as_char();
+                    // `a #b`, `b 'a` (raw strings or prefixes), `0 .1`, and
+                    // `< <`, `/ /` and so on
+                    let sep = match *last {
+                        Last::AlonePunct(p) => JOINABLE
+                            .iter()
+                            .any(|s| s.starts_with(p) && s[1..].starts_with(c)),
+                        Last::Word => matches!(c, '#' | '\''),
+                        Last::Literal => c == '.',
+                        _ => false,
+                    };
+                    if sep {
+                        *res += " ";
+                    }
+                    res.push(c);
+                    *last = match punct.spacing() {
+                        Spacing::Alone => Last::AlonePunct(c),
+                        Spacing::Joint => Last::Other,
+                    };
+                }
+            }
+        }
+    }
+
+    const JOINABLE: &[&str] = &[
+        "!=", "%=", "&&", "&=", "*=", "+=", "-=", "->", "..", "/*", "//", "/=",
+        "::", "<-", "<<", "<=", "==", "=>", ">=", ">>", "^=", "|=", "||",
+    ];
+
+    let mut res = "".to_owned();
+    print(ts, &mut res, &mut Last::None);
+    res
+}
+
+#[test]
+fn renaming() {
+    let src = r#"
+pub fn dist(origin: i64, target: i64) -> i64 {
+    let delta = helper(origin - target);
+    let width = 3;
+    println!("{delta:>width$}");
+    delta + LIMIT
+}
+fn helper(value: i64) -> i64 { value.abs() }
+const LIMIT: i64 = 10;
+macro_rules! sq {
+    ( $e:expr ) => { $e * $e };
+}
+"#;
+    let crates =
+        BTreeMap::from([(Crate::new("a".into(), "b".into()), src.into())]);
+    let minified = &minify("fn main() { dist(1, 2); }", &crates)
+        .into_values()
+        .next()
+        .unwrap();
+
+    let expected = r#"pub fn dist(b:i64,c:i64)->i64{let delta=a(b-c);let width=3;println!("{delta:>width$}");delta+B}
+fn a(b:i64)->i64{b.abs()}
+const B:i64=10;
+macro_rules!sq{($e:expr)=>{$e*$e};}
+"#;
+    assert_eq!(minified.code, expected);
+    assert_eq!(minified.lines, [2, 8, 9, 10]);
+}
+
+#[test]
+fn spacing() {
+    let test = |s: &str| print_tokens(s.parse().unwrap());
+    assert_eq!(test("a < < b"), "a< <b");
+    assert_eq!(test("a << b"), "a<<b");
+    assert_eq!(test("x: &'a u8"), "x:&'a u8");
+    assert_eq!(test("break 'outer"), "break 'outer");
+    assert_eq!(test("t.0 .1"), "t.0 .1");
+    assert_eq!(test("1 as u8 + r #x"), "1 as u8+r #x");
+}
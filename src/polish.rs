@@ -1,76 +1,464 @@
-use quote::quote;
-use syn::{parse_file, spanned::Spanned, visit_mut::VisitMut};
-
-use crate::library::LIBRARY_NAME;
+use std::collections::BTreeMap;
 
-pub fn polish_library(src: &str, cat: &str, cr: &str) -> String {
-    let src_ascii: String = src.chars().filter(|&c| c.is_ascii()).collect();
-    let mut ast = parse_file(&src_ascii).unwrap();
+use proc_macro2::{LineColumn, Literal, Span, TokenStream, TokenTree};
+use quote::quote;
+use syn::{
+    parse_file,
+    punctuated::{Pair, Punctuated},
+    spanned::Spanned,
+    visit_mut::{self, VisitMut},
+};
+
+use crate::{cfg::Cfg, edition::Edition, macro_deps::visit_refs};
+
+/// `module` is the path to the crate in the bundled code, as
+/// `["nekolib", "ds", "fenwick"]`, with which `$crate::` in the macros is
+/// rewritten.  The macros are left as is if it is empty.
+pub fn polish_library(
+    src: &str,
+    module: &[&str],
+    edition: Edition,
+    cfg: &Cfg,
+) -> Result<String, syn::Error> {
+    let macro_paths = BTreeMap::new();
+    polish_library_with_lines(src, module, &macro_paths, edition, cfg)
+        .map(|(res, _)| res)
+}
 
+/// Same as `polish_library`, but also returns the line in `src` each line
+/// of the result comes from.
+///
+/// `macro_paths` maps the references to other crates in the macros, in the
+/// form of `MacroDep::path`, to the paths to the crates in the bundled code.
+pub fn polish_library_with_lines(
+    src: &str,
+    module: &[&str],
+    macro_paths: &BTreeMap<Vec<String>, Vec<String>>,
+    edition: Edition,
+    cfg: &Cfg,
+) -> Result<(String, Vec<usize>), syn::Error> {
+    let mut ast = parse_file(src)?;
+
+    StripCfg(cfg).visit_file_mut(&mut ast);
     remove_attrs_by_ident(&mut ast.attrs, "doc");
     remove_doc_comments(&mut ast);
-    remove_test_items(&mut ast.items);
-    remove_macro_exports(&mut ast);
+    if edition == Edition::E2015 {
+        anchor_use_paths(&mut ast);
+    } else {
+        // re-exported by `pub(crate) use` instead
+        remove_macro_exports(&mut ast);
+    }
 
-    restore_macro_sources(&ast, cat, cr)
+    let macro_paths = MacroPaths { module, others: macro_paths };
+    restore_macro_sources(&ast, (!module.is_empty()).then_some(&macro_paths))
 }
 
-fn restore_macro_sources(ast: &syn::File, cat: &str, cr: &str) -> String {
+fn restore_macro_sources(
+    ast: &syn::File,
+    macro_paths: Option<&MacroPaths>,
+) -> Result<(String, Vec<usize>), syn::Error> {
     let src_tk = (quote! { #ast }).to_string();
-    let ast_tk = parse_file(&src_tk).unwrap();
+    let ast_tk = parse_file(&src_tk)?;
 
-    let crate_path = format!("$crate::{LIBRARY_NAME}::{cat}::{cr}::");
-    let mut res = "".to_owned();
-    for (item, item_tk) in ast.items.iter().zip(&ast_tk.items) {
-        if let syn::Item::Macro(item) = item {
-            if !res.is_empty() && !res.ends_with("\n") {
-                res += "\n";
+    let mut res = ("".to_owned(), vec![]);
+    restore_items(&ast.items, &ast_tk.items, macro_paths, &mut res)?;
+    Ok(res)
+}
+
+/// How the paths in the macros are rewritten in the bundled code, where
+/// `$crate` is the user's crate.
+struct MacroPaths<'a> {
+    module: &'a [&'a str],
+    others: &'a BTreeMap<Vec<String>, Vec<String>>,
+}
+
+impl MacroPaths<'_> {
+    fn rewrite(&self, src: &str) -> String {
+        let own = format!("$crate::{}", self.module.join("::"));
+        let Ok(ts) = src.parse::<TokenStream>() else {
+            return src.replace("$crate::", &format!("{own}::"));
+        };
+        let mut edits = vec![];
+        visit_refs(ts, &mut |path, [start, end]| {
+            let to = match (self.others.get(&path), &path[..]) {
+                (Some(to), [name]) => {
+                    format!("$crate::{}::{name}", to.join("::"))
+                }
+                (Some(to), _) => format!("$crate::{}", to.join("::")),
+                (None, [head, _]) if head == "$crate" => own.clone(),
+                (None, _) => return,
+            };
+            edits.push((start.start(), end.end(), to));
+        });
+
+        let offset = offset_fn(src);
+        let mut res = "".to_owned();
+        let mut pos = 0;
+        for (start, end, to) in edits {
+            res += &src[pos..offset(start)];
+            res += &to;
+            pos = offset(end);
+        }
+        res + &src[pos..]
+    }
+}
+
+// Each item is put on its own line(s), so that lines can be traced back.
+fn restore_items(
+    items: &[syn::Item],
+    items_tk: &[syn::Item],
+    macro_paths: Option<&MacroPaths>,
+    res: &mut (String, Vec<usize>),
+) -> Result<(), syn::Error> {
+    let source_text = |span: Span| {
+        span.source_text().ok_or_else(|| {
+            syn::Error::new(span, "the source of the item is unknown")
+        })
+    };
+    let push = |res: &mut (String, Vec<usize>), text: &str, line, step| {
+        for i in 0..text.split('\n').count() {
+            res.1.push(line + i * step);
+        }
+        res.0 += text;
+        res.0 += "\n";
+    };
+
+    for (item, item_tk) in items.iter().zip(items_tk) {
+        let line = item.span().start().line;
+        match (item, item_tk) {
+            (syn::Item::Macro(item), _) => {
+                let tmp = strip_comments(&source_text(item.span())?)
+                    .replace("\n", &format!("{:<13}", '\n'));
+                if let Some(macro_paths) = macro_paths {
+                    push(res, &macro_paths.rewrite(&tmp), line, 1);
+                } else {
+                    push(res, &tmp, line, 1);
+                }
             }
-            let tmp = item
-                .span()
-                .source_text()
-                .unwrap()
-                .replace("\n", &format!("{:<13}", '\n'));
-            if !cat.is_empty() && !cr.is_empty() {
-                res += &tmp.replace("$crate::", &crate_path);
-            } else {
-                res += &tmp;
+            (
+                syn::Item::Mod(syn::ItemMod {
+                    content: Some((_, inner)), ..
+                }),
+                syn::Item::Mod(
+                    item_tk @ syn::ItemMod {
+                        content: Some((_, inner_tk)), ..
+                    },
+                ),
+            ) => {
+                let syn::ItemMod { attrs, vis, unsafety, ident, .. } = item_tk;
+                let header = quote! { #(#attrs)* #vis #unsafety mod #ident };
+                push(res, &format!("{header} {{"), line, 0);
+                restore_items(inner, inner_tk, macro_paths, res)?;
+                push(res, "}", item.span().end().line, 0);
             }
-            res += "\n";
-        } else {
-            res += &item_tk.span().source_text().unwrap();
+            _ => push(res, &source_text(item_tk.span())?, line, 0),
+        }
+    }
+    Ok(())
+}
+
+/// Removes the comments in `src`, which are not in the AST of macros.  The
+/// line breaks are kept, but the trailing spaces are not.
+fn strip_comments(src: &str) -> String {
+    let Ok(ts) = src.parse::<TokenStream>() else { return src.to_owned() };
+    let mut spans = vec![];
+    token_spans(ts, &mut |span, _| spans.push((span.start(), span.end())));
+    spans.sort();
+
+    let offset = offset_fn(src);
+    let mut res = "".to_owned();
+    let mut pos = 0;
+    for (start, end) in spans {
+        let (start, end) = (offset(start), offset(end));
+        // doc comments are tokenized into overlapping spans
+        if start >= pos {
+            res += &strip_gap(&src[pos..start]);
+            pos = start;
+        }
+        if end > pos {
+            res += &src[pos..end];
+            pos = end;
         }
     }
+    res + &strip_gap(&src[pos..])
+}
 
+/// Removes the comments in whitespaces between tokens.
+fn strip_gap(gap: &str) -> String {
+    let mut res = "".to_owned();
+    let mut rest = gap;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("//") {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        } else if rest.starts_with("/*") {
+            // block comments may be nested
+            let mut depth = 0;
+            while !rest.is_empty() {
+                if rest.starts_with("/*") {
+                    depth += 1;
+                    rest = &rest[2..];
+                } else if rest.starts_with("*/") {
+                    depth -= 1;
+                    rest = &rest[2..];
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    rest = &rest[rest.chars().next().unwrap().len_utf8()..];
+                }
+            }
+        } else {
+            if c == '\n' {
+                res.truncate(res.trim_end_matches([' ', '\t']).len());
+            }
+            res.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
     res
 }
 
+/// Escapes non-ASCII characters in string and character literals into
+/// `\u{...}`, for judges that reject non-ASCII source code.  Raw string
+/// literals are turned into non-raw ones.  Lines are kept as they are.
+pub fn escape_non_ascii(src: &str) -> Result<String, syn::Error> {
+    let ts: TokenStream = syn::parse_str(src)?;
+    let mut replaced = vec![];
+    token_spans(ts, &mut |span, lit| {
+        let Some(lit) = lit.filter(|lit| !lit.to_string().is_ascii()) else {
+            return;
+        };
+        let escaped = match syn::Lit::new(lit.clone()) {
+            syn::Lit::Str(s) => {
+                format!("\"{}\"{}", escape(&s.value()), s.suffix())
+            }
+            syn::Lit::Char(c) => {
+                format!("'{}'{}", escape(&c.value().to_string()), c.suffix())
+            }
+            _ => return,
+        };
+        replaced.push((span.start(), span.end(), escaped));
+    });
+
+    let offset = offset_fn(src);
+    let mut res = "".to_owned();
+    let mut pos = 0;
+    for (start, end, escaped) in replaced {
+        res += &src[pos..offset(start)];
+        res += &escaped;
+        pos = offset(end);
+    }
+    Ok(res + &src[pos..])
+}
+
+fn escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            // keep the lines
+            '\n' => c.to_string(),
+            _ if c.is_ascii() => c.escape_default().to_string(),
+            _ => c.escape_unicode().to_string(),
+        })
+        .collect()
+}
+
+/// Calls `f` with the span of each token in `ts` in order, along with the
+/// token itself if it is a literal.
+fn token_spans(ts: TokenStream, f: &mut impl FnMut(Span, Option<Literal>)) {
+    for tt in ts {
+        match tt {
+            TokenTree::Group(group) => {
+                f(group.span_open(), None);
+                token_spans(group.stream(), f);
+                f(group.span_close(), None);
+            }
+            TokenTree::Literal(lit) => f(lit.span(), Some(lit)),
+            _ => f(tt.span(), None),
+        }
+    }
+}
+
+/// Returns a function converting a position in `src` into a byte offset.
+pub(crate) fn offset_fn(src: &str) -> impl Fn(LineColumn) -> usize + '_ {
+    let mut line_starts = vec![0];
+    line_starts.extend(src.match_indices('\n').map(|(i, _)| i + 1));
+    move |pos: LineColumn| {
+        let start = line_starts[pos.line - 1];
+        let line = src[start..].split('\n').next().unwrap();
+        start
+            + line.char_indices().nth(pos.column).map_or(line.len(), |(i, _)| i)
+    }
+}
+
 fn remove_attrs_by_ident(attrs: &mut Vec<syn::Attribute>, ident: &str) {
     attrs.retain(|attr| !attr.meta.path().is_ident(ident));
 }
 
-fn remove_test_items(items: &mut Vec<syn::Item>) {
-    items.retain(|item| {
-        let attrs = match item {
-            syn::Item::Const(item) => &item.attrs,
-            syn::Item::Enum(item) => &item.attrs,
-            syn::Item::ExternCrate(item) => &item.attrs,
-            syn::Item::Fn(item) => &item.attrs,
-            syn::Item::ForeignMod(item) => &item.attrs,
-            syn::Item::Impl(item) => &item.attrs,
-            syn::Item::Macro(item) => &item.attrs,
-            syn::Item::Mod(item) => &item.attrs,
-            syn::Item::Static(item) => &item.attrs,
-            syn::Item::Struct(item) => &item.attrs,
-            syn::Item::Trait(item) => &item.attrs,
-            syn::Item::TraitAlias(item) => &item.attrs,
-            syn::Item::Type(item) => &item.attrs,
-            syn::Item::Union(item) => &item.attrs,
-            syn::Item::Use(item) => &item.attrs,
-            _ => unimplemented!(),
-        };
-        attrs.iter().all(|attr| !is_test_attr(&attr))
-    });
+fn item_attrs(item: &mut syn::Item) -> Option<&mut Vec<syn::Attribute>> {
+    match item {
+        syn::Item::Const(item) => Some(&mut item.attrs),
+        syn::Item::Enum(item) => Some(&mut item.attrs),
+        syn::Item::ExternCrate(item) => Some(&mut item.attrs),
+        syn::Item::Fn(item) => Some(&mut item.attrs),
+        syn::Item::ForeignMod(item) => Some(&mut item.attrs),
+        syn::Item::Impl(item) => Some(&mut item.attrs),
+        syn::Item::Macro(item) => Some(&mut item.attrs),
+        syn::Item::Mod(item) => Some(&mut item.attrs),
+        syn::Item::Static(item) => Some(&mut item.attrs),
+        syn::Item::Struct(item) => Some(&mut item.attrs),
+        syn::Item::Trait(item) => Some(&mut item.attrs),
+        syn::Item::TraitAlias(item) => Some(&mut item.attrs),
+        syn::Item::Type(item) => Some(&mut item.attrs),
+        syn::Item::Union(item) => Some(&mut item.attrs),
+        syn::Item::Use(item) => Some(&mut item.attrs),
+        _ => None,
+    }
+}
+
+fn expr_attrs(expr: &mut syn::Expr) -> Option<&mut Vec<syn::Attribute>> {
+    match expr {
+        syn::Expr::Array(expr) => Some(&mut expr.attrs),
+        syn::Expr::Assign(expr) => Some(&mut expr.attrs),
+        syn::Expr::Async(expr) => Some(&mut expr.attrs),
+        syn::Expr::Await(expr) => Some(&mut expr.attrs),
+        syn::Expr::Binary(expr) => Some(&mut expr.attrs),
+        syn::Expr::Block(expr) => Some(&mut expr.attrs),
+        syn::Expr::Break(expr) => Some(&mut expr.attrs),
+        syn::Expr::Call(expr) => Some(&mut expr.attrs),
+        syn::Expr::Cast(expr) => Some(&mut expr.attrs),
+        syn::Expr::Closure(expr) => Some(&mut expr.attrs),
+        syn::Expr::Const(expr) => Some(&mut expr.attrs),
+        syn::Expr::Continue(expr) => Some(&mut expr.attrs),
+        syn::Expr::Field(expr) => Some(&mut expr.attrs),
+        syn::Expr::ForLoop(expr) => Some(&mut expr.attrs),
+        syn::Expr::Group(expr) => Some(&mut expr.attrs),
+        syn::Expr::If(expr) => Some(&mut expr.attrs),
+        syn::Expr::Index(expr) => Some(&mut expr.attrs),
+        syn::Expr::Infer(expr) => Some(&mut expr.attrs),
+        syn::Expr::Let(expr) => Some(&mut expr.attrs),
+        syn::Expr::Lit(expr) => Some(&mut expr.attrs),
+        syn::Expr::Loop(expr) => Some(&mut expr.attrs),
+        syn::Expr::Macro(expr) => Some(&mut expr.attrs),
+        syn::Expr::Match(expr) => Some(&mut expr.attrs),
+        syn::Expr::MethodCall(expr) => Some(&mut expr.attrs),
+        syn::Expr::Paren(expr) => Some(&mut expr.attrs),
+        syn::Expr::Path(expr) => Some(&mut expr.attrs),
+        syn::Expr::Range(expr) => Some(&mut expr.attrs),
+        syn::Expr::RawAddr(expr) => Some(&mut expr.attrs),
+        syn::Expr::Reference(expr) => Some(&mut expr.attrs),
+        syn::Expr::Repeat(expr) => Some(&mut expr.attrs),
+        syn::Expr::Return(expr) => Some(&mut expr.attrs),
+        syn::Expr::Struct(expr) => Some(&mut expr.attrs),
+        syn::Expr::Try(expr) => Some(&mut expr.attrs),
+        syn::Expr::TryBlock(expr) => Some(&mut expr.attrs),
+        syn::Expr::Tuple(expr) => Some(&mut expr.attrs),
+        syn::Expr::Unary(expr) => Some(&mut expr.attrs),
+        syn::Expr::Unsafe(expr) => Some(&mut expr.attrs),
+        syn::Expr::While(expr) => Some(&mut expr.attrs),
+        syn::Expr::Yield(expr) => Some(&mut expr.attrs),
+        _ => None,
+    }
+}
+
+/// Removes items whose `#[cfg]` is false, such as `#[test]` and
+/// `#[cfg(test)]` ones, and the `#[cfg]`s that are true.  Besides items at
+/// every level, associated items, statements, fields, variants and match
+/// arms are also subject to it.
+struct StripCfg<'a>(&'a Cfg);
+
+impl VisitMut for StripCfg<'_> {
+    fn visit_file_mut(&mut self, node: &mut syn::File) {
+        if !self.0.apply(&mut node.attrs) {
+            node.items.clear();
+        }
+        node.items.retain_mut(|item| self.apply_item(item));
+        visit_mut::visit_file_mut(self, node);
+    }
+
+    fn visit_item_mod_mut(&mut self, node: &mut syn::ItemMod) {
+        if let Some((_, items)) = &mut node.content {
+            items.retain_mut(|item| self.apply_item(item));
+        }
+        visit_mut::visit_item_mod_mut(self, node);
+    }
+
+    fn visit_item_impl_mut(&mut self, node: &mut syn::ItemImpl) {
+        node.items.retain_mut(|item| match item {
+            syn::ImplItem::Const(item) => self.0.apply(&mut item.attrs),
+            syn::ImplItem::Fn(item) => self.0.apply(&mut item.attrs),
+            syn::ImplItem::Type(item) => self.0.apply(&mut item.attrs),
+            syn::ImplItem::Macro(item) => self.0.apply(&mut item.attrs),
+            _ => true,
+        });
+        visit_mut::visit_item_impl_mut(self, node);
+    }
+
+    fn visit_item_trait_mut(&mut self, node: &mut syn::ItemTrait) {
+        node.items.retain_mut(|item| match item {
+            syn::TraitItem::Const(item) => self.0.apply(&mut item.attrs),
+            syn::TraitItem::Fn(item) => self.0.apply(&mut item.attrs),
+            syn::TraitItem::Type(item) => self.0.apply(&mut item.attrs),
+            syn::TraitItem::Macro(item) => self.0.apply(&mut item.attrs),
+            _ => true,
+        });
+        visit_mut::visit_item_trait_mut(self, node);
+    }
+
+    fn visit_block_mut(&mut self, node: &mut syn::Block) {
+        node.stmts.retain_mut(|stmt| match stmt {
+            syn::Stmt::Local(local) => self.0.apply(&mut local.attrs),
+            syn::Stmt::Item(item) => self.apply_item(item),
+            syn::Stmt::Macro(mac) => self.0.apply(&mut mac.attrs),
+            syn::Stmt::Expr(expr, _) => match expr_attrs(expr) {
+                Some(attrs) => self.0.apply(attrs),
+                None => true,
+            },
+        });
+        visit_mut::visit_block_mut(self, node);
+    }
+
+    fn visit_fields_named_mut(&mut self, node: &mut syn::FieldsNamed) {
+        self.retain(&mut node.named, |f| &mut f.attrs);
+        visit_mut::visit_fields_named_mut(self, node);
+    }
+
+    fn visit_fields_unnamed_mut(&mut self, node: &mut syn::FieldsUnnamed) {
+        self.retain(&mut node.unnamed, |f| &mut f.attrs);
+        visit_mut::visit_fields_unnamed_mut(self, node);
+    }
+
+    fn visit_item_enum_mut(&mut self, node: &mut syn::ItemEnum) {
+        self.retain(&mut node.variants, |v| &mut v.attrs);
+        visit_mut::visit_item_enum_mut(self, node);
+    }
+
+    fn visit_expr_match_mut(&mut self, node: &mut syn::ExprMatch) {
+        node.arms.retain_mut(|arm| self.0.apply(&mut arm.attrs));
+        visit_mut::visit_expr_match_mut(self, node);
+    }
+}
+
+impl StripCfg<'_> {
+    fn apply_item(&self, item: &mut syn::Item) -> bool {
+        match item_attrs(item) {
+            Some(attrs) => self.0.apply(attrs),
+            None => true,
+        }
+    }
+
+    fn retain<T, P>(
+        &self,
+        nodes: &mut Punctuated<T, P>,
+        attrs: impl Fn(&mut T) -> &mut Vec<syn::Attribute>,
+    ) {
+        *nodes = std::mem::take(nodes)
+            .into_pairs()
+            .filter_map(|pair| {
+                let (mut node, punct) = pair.into_tuple();
+                self.0.apply(attrs(&mut node)).then(|| Pair::new(node, punct))
+            })
+            .collect();
+    }
 }
 
 struct RemoveDocComments;
@@ -140,19 +528,45 @@ fn remove_macro_exports(ast: &mut syn::File) {
     RemoveMacroExports.visit_file_mut(ast);
 }
 
-fn is_test_attr(attr: &syn::Attribute) -> bool {
-    match &attr.meta {
-        syn::Meta::Path(path) => path.is_ident("test"),
-        syn::Meta::List(list) if list.path.is_ident("cfg") => {
-            list.path.is_ident("cfg") && list.tokens.to_string() == "test"
+struct AnchorUsePaths;
+
+impl VisitMut for AnchorUsePaths {
+    fn visit_item_use_mut(&mut self, node: &mut syn::ItemUse) {
+        if node.leading_colon.is_none() {
+            anchor_use_tree(&mut node.tree);
+        }
+    }
+}
+
+// `use foo::Bar;` is relative to the crate root in Rust 2015, so we make it
+// `use self::foo::Bar;`.
+fn anchor_use_tree(tree: &mut syn::UseTree) {
+    let first = match tree {
+        syn::UseTree::Path(path) => &path.ident,
+        syn::UseTree::Name(name) => &name.ident,
+        syn::UseTree::Rename(rename) => &rename.ident,
+        syn::UseTree::Glob(_) => return,
+        syn::UseTree::Group(group) => {
+            for tree in &mut group.items {
+                anchor_use_tree(tree);
+            }
+            return;
         }
-        _ => false,
+    };
+    if ["self", "super", "crate", "std", "core", "alloc"]
+        .iter()
+        .any(|s| first == s)
+    {
+        return;
     }
+    let inner = tree.clone();
+    *tree = syn::parse_quote! { self::#inner };
 }
 
+fn anchor_use_paths(ast: &mut syn::File) { AnchorUsePaths.visit_file_mut(ast); }
+
 #[test]
 fn removal() {
-    use proc_macro2::TokenStream;
     use quote::quote;
 
     let src = r#"
@@ -197,7 +611,8 @@ mod tests {
 }
 "#;
 
-    let actual = polish_library(src, "", "");
+    let actual =
+        polish_library(src, &[], Edition::E2021, &Cfg::default()).unwrap();
     let expected = r#"
 #[allow(unused)]
 fn one(x: i32) -> i32 { 1 }
@@ -222,19 +637,198 @@ mod libs {
 "#;
 
     let actual = parse_file(&actual).unwrap();
-    let expected = parse_file(&expected).unwrap();
+    let expected = parse_file(expected).unwrap();
+
+    let actual = quote! { #actual };
+    let expected = quote! { #expected };
+
+    eprintln!("{actual}");
+    eprintln!("{expected}");
+    assert_eq!(actual.to_string(), expected.to_string());
+}
+
+#[test]
+fn nested_removal() {
+    use quote::quote;
+
+    let src = r#"
+struct Foo;
+
+impl Foo {
+    fn foo(&self) {}
+    #[cfg(test)]
+    fn helper(&self) {}
+}
+
+trait Bar {
+    fn bar(&self);
+    #[cfg(all(test, unix))]
+    fn helper(&self) {}
+}
+
+fn baz() -> i32 {
+    #[cfg(test)]
+    fn helper() {}
+    #[cfg(any(test, test))]
+    let x = 1;
+    #[cfg(any(test, unix))]
+    let y = 2;
+    #[cfg(not(test))]
+    let z = 3;
+    1
+}
+
+mod libs {
+    mod internal {
+        #[test]
+        fn test() {}
+    }
+}
+"#;
+
+    let actual =
+        polish_library(src, &[], Edition::E2021, &Cfg::default()).unwrap();
+    let expected = r#"
+struct Foo;
+
+impl Foo {
+    fn foo(&self) {}
+}
+
+trait Bar {
+    fn bar(&self);
+}
+
+fn baz() -> i32 {
+    #[cfg(any(test, unix))]
+    let y = 2;
+    let z = 3;
+    1
+}
+
+mod libs {
+    mod internal {}
+}
+"#;
+
+    let actual = parse_file(&actual).unwrap();
+    let expected = parse_file(expected).unwrap();
 
-    let actual = TokenStream::from(quote! { #actual });
-    let expected = TokenStream::from(quote! { #expected });
+    let actual = quote! { #actual };
+    let expected = quote! { #expected };
 
     eprintln!("{actual}");
     eprintln!("{expected}");
     assert_eq!(actual.to_string(), expected.to_string());
 }
 
+#[test]
+fn cfg_evaluation() {
+    use quote::quote;
+
+    let src = r#"
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(not(feature = "std"))]
+use std::collections::BTreeMap;
+
+#[cfg_attr(feature = "std", derive(Debug))]
+#[cfg_attr(windows, derive(Clone))]
+struct Foo {
+    a: i32,
+    #[cfg(target_os = "windows")]
+    b: i32,
+}
+
+enum Bar {
+    A,
+    #[cfg(any(target_os = "linux", feature = "std"))]
+    B,
+}
+
+#[cfg(target_pointer_width = "64")]
+fn width() -> u32 { 64 }
+"#;
+
+    let cfg = Cfg::new([
+        ("feature".to_owned(), Some("std".to_owned())),
+        ("target_os".to_owned(), Some("linux".to_owned())),
+    ]);
+    let actual = polish_library(src, &[], Edition::E2021, &cfg).unwrap();
+    let expected = r#"
+use std::collections::HashMap;
+
+#[derive(Debug)]
+#[cfg_attr(windows, derive(Clone))]
+struct Foo {
+    a: i32,
+}
+
+enum Bar {
+    A,
+    B,
+}
+
+#[cfg(target_pointer_width = "64")]
+fn width() -> u32 { 64 }
+"#;
+
+    let actual = parse_file(&actual).unwrap();
+    let expected = parse_file(expected).unwrap();
+
+    let actual = quote! { #actual };
+    let expected = quote! { #expected };
+
+    eprintln!("{actual}");
+    eprintln!("{expected}");
+    assert_eq!(actual.to_string(), expected.to_string());
+}
+
+#[test]
+fn cfg_statements() {
+    use quote::quote;
+
+    let src = r#"
+fn foo() -> i32 {
+    #[cfg(feature = "big")]
+    let x = 100;
+    #[cfg(not(feature = "big"))]
+    let x = 1;
+    #[cfg(feature = "big")]
+    bar(x);
+    #[cfg(not(feature = "big"))]
+    {
+        baz(x);
+    }
+    #[cfg(unix)]
+    qux();
+    x
+}
+"#;
+
+    let cfg = Cfg::new([("feature".to_owned(), Some("big".to_owned()))]);
+    let actual = polish_library(src, &[], Edition::E2021, &cfg).unwrap();
+    let expected = r#"
+fn foo() -> i32 {
+    let x = 100;
+    bar(x);
+    #[cfg(unix)]
+    qux();
+    x
+}
+"#;
+
+    let actual = parse_file(&actual).unwrap();
+    let expected = parse_file(expected).unwrap();
+
+    let actual = quote! { #actual };
+    let expected = quote! { #expected };
+
+    assert_eq!(actual.to_string(), expected.to_string());
+}
+
 #[test]
 fn macro_export() {
-    use proc_macro2::TokenStream;
     use quote::quote;
 
     let src = r#"
@@ -244,20 +838,109 @@ macro_rules! foo {
 }
 "#;
 
-    let actual = polish_library(src, "", "");
+    let actual =
+        polish_library(src, &[], Edition::E2021, &Cfg::default()).unwrap();
     let expected = "macro_rules! foo { () => {} }";
 
     let actual = parse_file(&actual).unwrap();
-    let expected = parse_file(&expected).unwrap();
+    let expected = parse_file(expected).unwrap();
 
-    let actual = TokenStream::from(quote! { #actual });
-    let expected = TokenStream::from(quote! { #expected });
+    let actual = quote! { #actual };
+    let expected = quote! { #expected };
 
     eprintln!("{actual}");
     eprintln!("{expected}");
     assert_eq!(actual.to_string(), expected.to_string());
 }
 
+#[test]
+fn macro_crate_path() {
+    let src = r#"
+#[macro_export]
+macro_rules! sum {
+    ($($x:expr),*) => { $crate::fold(0, &[$($x),*]) }
+}
+"#;
+
+    let module = ["snippets", "iter", "fold"];
+    let actual =
+        polish_library(src, &module, Edition::E2021, &Cfg::default()).unwrap();
+    assert!(actual.contains("$crate::snippets::iter::fold::fold(0,"));
+}
+
+#[test]
+fn edition_2015() {
+    use quote::quote;
+
+    let src = r#"
+use std::fmt;
+use inner::{Foo, bar};
+
+#[macro_export]
+macro_rules! foo {
+    () => {}
+}
+"#;
+
+    let actual =
+        polish_library(src, &[], Edition::E2015, &Cfg::default()).unwrap();
+    let expected = r#"
+use std::fmt;
+use self::inner::{Foo, bar};
+
+#[macro_export]
+macro_rules! foo { () => {} }
+"#;
+
+    let actual = parse_file(&actual).unwrap();
+    let expected = parse_file(expected).unwrap();
+
+    let actual = quote! { #actual };
+    let expected = quote! { #expected };
+
+    assert_eq!(actual.to_string(), expected.to_string());
+}
+
+#[test]
+fn non_ascii() {
+    let src = r##"
+//! 説明
+/// 猫
+fn ねこ() -> (&'static str, char) { ("にゃー", 'ん') } // 鳴き声
+const RAW: &str = r#""猫""#;
+macro_rules! m {
+    // 文字列
+    () => { "ねこ" /* ねこ /* ねこ */ */ };  
+    /// doc
+    ($x:expr) => { $x }
+}
+"##;
+
+    let actual =
+        polish_library(src, &[], Edition::E2021, &Cfg::default()).unwrap();
+    let expected = r##"fn ねこ () -> (& 'static str , char) { ("にゃー" , 'ん') }
+const RAW : & str = r#""猫""# ;
+macro_rules! m {
+            
+                () => { "ねこ"  };
+                /// doc
+                ($x:expr) => { $x }
+            }
+"##;
+    assert_eq!(actual, expected);
+
+    let expected = r#"fn ねこ () -> (& 'static str , char) { ("\u{306b}\u{3083}\u{30fc}" , '\u{3093}') }
+const RAW : & str = "\"\u{732b}\"" ;
+macro_rules! m {
+            
+                () => { "\u{306d}\u{3053}"  };
+                /// doc
+                ($x:expr) => { $x }
+            }
+"#;
+    assert_eq!(escape_non_ascii(&actual).unwrap(), expected);
+}
+
 #[test]
 fn macro_fmt() {
     use syn::{spanned::Spanned, visit::Visit};
@@ -295,7 +978,7 @@ macro_rules! baz {
         }
     }
 
-    let file = syn::parse_file(&original).unwrap();
+    let file = syn::parse_file(original).unwrap();
     let mut vim = VisitItemMacro { spans: vec![] };
     vim.visit_file(&file);
     for span in vim.spans {
@@ -3,80 +3,264 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use syn::{parse_file, spanned::Spanned};
+use proc_macro2::LineColumn;
+use quote::ToTokens;
+use syn::{Token, parse_file, punctuated::Punctuated, spanned::Spanned};
 
-use crate::polish::polish_library;
+use crate::{cfg::Cfg, error::BundleError};
 
-pub fn resolve_nested_mod(path: &Path) -> String {
+/// `(path, line)` of the original file a line comes from, 1-indexed.
+pub type Origin = (PathBuf, usize);
+
+/// The directory where the files of submodules are looked for.
+///
+/// See <https://doc.rust-lang.org/reference/items/modules.html>.  In a
+/// non-mod-rs file `src/a/b.rs`, `dir` is `src/a` and `relative` is `b`,
+/// since `#[path]` of `mod c;` is relative to `src/a` but `mod c;` itself
+/// is `src/a/b/c.rs`.
+#[derive(Clone, Debug)]
+pub struct ModDir {
+    dir: PathBuf,
+    relative: Option<String>,
+}
+
+impl ModDir {
+    /// The directory of a file named as usual, e.g. `lib.rs`, `a/mod.rs` or
+    /// `a/b.rs`.
+    pub fn of_file(path: &Path) -> Self {
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        let relative = match stem {
+            "lib" | "main" | "mod" => None,
+            _ => Some(stem.to_owned()),
+        };
+        Self { dir: path.parent().unwrap().to_owned(), relative }
+    }
+
+    fn owned(&self) -> PathBuf {
+        match &self.relative {
+            Some(relative) => self.dir.join(relative),
+            None => self.dir.clone(),
+        }
+    }
+
+    /// The directory for the items inside `mod inline { .. }` in `file`.
+    fn inline(
+        &self,
+        item_mod: &syn::ItemMod,
+        cfg: &Cfg,
+        file: &Path,
+    ) -> Result<Self, BundleError> {
+        // `#[path]` on an inline module is a directory relative to `dir`.
+        let dir = match path_attr(item_mod, cfg, file)? {
+            Some(path) => self.dir.join(path),
+            None => self.owned().join(item_mod.ident.to_string()),
+        };
+        Ok(Self { dir, relative: None })
+    }
+}
+
+/// Returns the source with every `mod foo;` expanded to `mod foo { ... }`,
+/// along with the origin of each line and the files read.
+///
+/// The nested files are not polished one by one, as it would lose the
+/// origin of each line.  The caller polishes the whole result instead,
+/// which strips docs and `#[cfg(test)]` items in the nested modules too.
+pub fn resolve_nested_mod(
+    path: &Path,
+    cfg: &Cfg,
+) -> Result<(String, Vec<Origin>, Vec<PathBuf>), BundleError> {
+    let src = fs::read_to_string(path)
+        .map_err(|e| BundleError::io("read", path, e))?;
+    let mut res = ("".to_owned(), vec![], vec![]);
+    resolve_file(path, &src, &ModDir::of_file(path), cfg, &mut res)?;
+    Ok(res)
+}
+
+fn resolve_file(
+    path: &Path,
+    src: &str,
+    mod_dir: &ModDir,
+    cfg: &Cfg,
+    res: &mut (String, Vec<Origin>, Vec<PathBuf>),
+) -> Result<(), BundleError> {
     // When we find `mod foo;`, we should read the corresponding file
     // (see <https://doc.rust-lang.org/reference/items/modules.html>)
     // and return `mod foo { ... }`. The procedure should be called
     // recursively.
 
-    let src = fs::read_to_string(path).unwrap();
-    let src_ascii: String = src.chars().filter(|&c| c.is_ascii()).collect();
-    let ast = parse_file(&src_ascii).unwrap();
-
-    let mut src_resolved = "".to_owned();
-    for item in ast.items.iter() {
-        let text = item.span().source_text().unwrap();
-        if let syn::Item::Mod(item_mod) = item {
-            if item_mod.content.is_none() {
-                assert!(item_mod.semi.is_some());
-                let mut text = text;
-                let semi = text.pop();
-                assert_eq!(semi, Some(';'));
-                src_resolved += &text;
-
-                let mod_path = resolve_mod_source(path, item_mod);
-                src_resolved += "{";
-                src_resolved += &resolve_nested_mod(&mod_path);
-                src_resolved += "}";
+    res.2.push(path.to_owned());
+    let ast = parse_file(src)
+        .map_err(|e| BundleError::source_parse(Some(path), e))?;
+    let lines: Vec<_> = src.split('\n').collect();
+    resolve_items(&ast.items, &lines, path, mod_dir, cfg, res)
+}
+
+fn resolve_items(
+    items: &[syn::Item],
+    lines: &[&str],
+    path: &Path,
+    mod_dir: &ModDir,
+    cfg: &Cfg,
+    res: &mut (String, Vec<Origin>, Vec<PathBuf>),
+) -> Result<(), BundleError> {
+    let push = |res: &mut (String, Vec<_>, _), text: &str, line: usize| {
+        for i in 0..text.split('\n').count() {
+            res.1.push((path.to_owned(), line + i));
+        }
+        res.0 += text;
+        res.0 += "\n";
+    };
+
+    for item in items {
+        let (start, end) = (item.span().start(), item.span().end());
+        let syn::Item::Mod(item_mod) = item else {
+            push(res, &slice(lines, start, end), start.line);
+            continue;
+        };
+
+        // The file of a disabled module may not exist.
+        if cfg.is_disabled(&item_mod.attrs) {
+            continue;
+        }
+
+        let (header_end, inner) = match &item_mod.content {
+            None => {
+                let semi = item_mod.semi.unwrap().span.start();
+                let (mod_path, nested_dir) =
+                    resolve_mod_source(mod_dir, item_mod, cfg, path)?;
+                let src = fs::read_to_string(&mod_path).map_err(|source| {
+                    BundleError::MissingModule {
+                        path: mod_path.clone(),
+                        source,
+                    }
+                })?;
+                push(res, &(slice(lines, start, semi) + "{"), start.line);
+                resolve_file(&mod_path, &src, &nested_dir, cfg, res)?;
+                push(res, "}", end.line);
                 continue;
             }
+            Some((brace, inner)) => (brace.span.open().end(), inner),
+        };
 
-            // FIXME: we miss `mod inline { #[path = "other.rs"] mod inner; }`.
+        // `mod inline { .. }` may contain `mod foo;` as well.
+        push(res, &slice(lines, start, header_end), start.line);
+        for attr in &item_mod.attrs {
+            if let syn::AttrStyle::Inner(_) = attr.style {
+                let (start, end) = (attr.span().start(), attr.span().end());
+                push(res, &slice(lines, start, end), start.line);
+            }
         }
-        src_resolved += &text;
+        let inline_dir = mod_dir.inline(item_mod, cfg, path)?;
+        resolve_items(inner, lines, path, &inline_dir, cfg, res)?;
+        push(res, "}", end.line);
     }
+    Ok(())
+}
 
-    polish_library(&src_resolved, "", "")
+/// Returns the text in `[start, end)`.
+fn slice(lines: &[&str], start: LineColumn, end: LineColumn) -> String {
+    let column = |line: &str, column| {
+        line.char_indices().nth(column).map_or(line.len(), |(i, _)| i)
+    };
+    if start.line == end.line {
+        let line = lines[start.line - 1];
+        return line[column(line, start.column)..column(line, end.column)]
+            .to_owned();
+    }
+    let first = lines[start.line - 1];
+    let last = lines[end.line - 1];
+    let mut res = first[column(first, start.column)..].to_owned();
+    for line in &lines[start.line..end.line - 1] {
+        res += "\n";
+        res += line;
+    }
+    res += "\n";
+    res += &last[..column(last, end.column)];
+    res
 }
 
-pub fn resolve_mod_source(
-    main_path: &Path,
+/// `#[path = ".."]`, or `#[cfg_attr(pred, path = "..")]` if `pred` holds,
+/// of `item_mod` in `file`.
+///
+/// `pred` of such `cfg_attr`s depending on unknown options is reported as
+/// an error, since the file cannot be determined.
+fn path_attr(
     item_mod: &syn::ItemMod,
-) -> PathBuf {
-    let path_override = item_mod.attrs.iter().find_map(|attr| {
+    cfg: &Cfg,
+    file: &Path,
+) -> Result<Option<String>, BundleError> {
+    for attr in &item_mod.attrs {
         if let syn::AttrStyle::Inner(_) = attr.style {
-            return None;
+            continue;
         }
-        if let syn::Meta::NameValue(mnv) = &attr.meta {
-            let ident = mnv.path.get_ident()?.to_string();
-            if ident != "path" {
-                return None;
+        let metas = match &attr.meta {
+            syn::Meta::List(list) if list.path.is_ident("cfg_attr") => {
+                let args = list
+                    .parse_args_with(
+                        Punctuated::<syn::Meta, Token![,]>::parse_terminated,
+                    )
+                    .map_err(|e| BundleError::source_parse(Some(file), e))?;
+                let mut args = args.into_iter();
+                let Some(pred) = args.next() else { continue };
+                let metas: Vec<_> = args.collect();
+                if !metas.iter().any(|meta| meta.path().is_ident("path")) {
+                    continue;
+                }
+                match cfg.eval(&pred) {
+                    Some(true) => metas,
+                    Some(false) => continue,
+                    None => {
+                        let start = attr.span().start();
+                        return Err(BundleError::UnknownCfg {
+                            path: file.to_owned(),
+                            line: start.line,
+                            column: start.column + 1,
+                            pred: pred.to_token_stream().to_string(),
+                        });
+                    }
+                }
+            }
+            meta => vec![meta.clone()],
+        };
+        for meta in metas {
+            let syn::Meta::NameValue(mnv) = meta else { continue };
+            if !mnv.path.is_ident("path") {
+                continue;
             }
             if let syn::Expr::Lit(expr_lit) = &mnv.value {
                 if let syn::Lit::Str(lit_str) = &expr_lit.lit {
-                    return Some(lit_str.value());
+                    return Ok(Some(lit_str.value()));
                 }
             }
         }
-        None
-    });
-
-    let dir = main_path.parent().unwrap();
-    let main = PathBuf::from(main_path.file_name().unwrap());
-    let main_stem = main.file_stem().unwrap();
-    if let Some(path) = path_override {
-        dir.join(path)
+    }
+    Ok(None)
+}
+
+/// Returns the file of `mod foo;` in `file`, whose submodules are in
+/// `mod_dir`, along with the directory for the submodules in the file.
+pub fn resolve_mod_source(
+    mod_dir: &ModDir,
+    item_mod: &syn::ItemMod,
+    cfg: &Cfg,
+    file: &Path,
+) -> Result<(PathBuf, ModDir), BundleError> {
+    if let Some(path) = path_attr(item_mod, cfg, file)? {
+        // `#[path]` files behave like mod-rs files.
+        let path = mod_dir.dir.join(path);
+        let dir = path.parent().unwrap().to_owned();
+        return Ok((path, ModDir { dir, relative: None }));
+    }
+
+    let name = item_mod.ident.to_string();
+    let owned = mod_dir.owned();
+    let mod_rs = owned.join(&name).join("mod.rs");
+    if mod_rs.exists() {
+        let dir = owned.join(&name);
+        Ok((mod_rs, ModDir { dir, relative: None }))
     } else {
-        let basename = format!("{}.rs", item_mod.ident.to_string());
-        if main_stem == "mod" || main_stem == "lib" {
-            dir.join(basename)
-        } else {
-            dir.join(main_stem).join(basename)
-        }
+        let path = owned.join(format!("{name}.rs"));
+        Ok((path, ModDir { dir: owned, relative: Some(name) }))
     }
 }
 
@@ -95,16 +279,95 @@ mod tests_mod_path {
             (("src/a/b.rs", r#"#[path = "foo.rs"] mod c;"#), "src/a/foo.rs"),
             (("src/a/mod.rs", "mod c;"), "src/a/c.rs"),
             (("src/a/b.rs", "mod c;"), "src/a/b/c.rs"),
+            (
+                (
+                    "src/a/b.rs",
+                    r#"#[cfg_attr(not(test), path = "foo.rs")] mod c;"#,
+                ),
+                "src/a/foo.rs",
+            ),
+            (
+                ("src/a/b.rs", r#"#[cfg_attr(test, path = "foo.rs")] mod c;"#),
+                "src/a/b/c.rs",
+            ),
+            // inside inline modules
+            (("src/a/mod.rs", "mod inline { mod c; }"), "src/a/inline/c.rs"),
+            (("src/a/b.rs", "mod inline { mod c; }"), "src/a/b/inline/c.rs"),
+            (
+                (
+                    "src/a/mod.rs",
+                    r#"mod inline { #[path = "other.rs"] mod c; }"#,
+                ),
+                "src/a/inline/other.rs",
+            ),
+            (
+                ("src/a/b.rs", r#"mod inline { #[path = "other.rs"] mod c; }"#),
+                "src/a/b/inline/other.rs",
+            ),
+            (
+                (
+                    "src/a/b.rs",
+                    r#"#[path = "dir"] mod inline { #[path = "other.rs"] mod c; }"#,
+                ),
+                "src/a/dir/other.rs",
+            ),
+            (("src/a/b.rs", "mod x { mod y { mod c; } }"), "src/a/b/x/y/c.rs"),
         ];
 
+        let cfg = Cfg::default();
         for ((main_path, file), mod_path) in SUITE {
             let file = parse_file(file).unwrap();
-            let item = match &file.items[0] {
+            let mut mod_dir = ModDir::of_file(main_path.as_ref());
+            let mut item = match &file.items[0] {
                 syn::Item::Mod(i) => i,
                 _ => unreachable!(),
             };
-            let actual = resolve_mod_source(main_path.as_ref(), item);
+            while let Some((_, inner)) = &item.content {
+                mod_dir =
+                    mod_dir.inline(item, &cfg, main_path.as_ref()).unwrap();
+                item = match &inner[0] {
+                    syn::Item::Mod(i) => i,
+                    _ => unreachable!(),
+                };
+            }
+            let (actual, _) =
+                resolve_mod_source(&mod_dir, item, &cfg, main_path.as_ref())
+                    .unwrap();
             assert_eq!(actual, PathBuf::from(mod_path));
         }
+
+        let file =
+            parse_file(r#"#[cfg_attr(unix, path = "foo.rs")] mod c;"#).unwrap();
+        let syn::Item::Mod(item) = &file.items[0] else { unreachable!() };
+        let main_path = Path::new("src/a/b.rs");
+        let mod_dir = ModDir::of_file(main_path);
+        let err = resolve_mod_source(&mod_dir, item, &cfg, main_path);
+        assert!(matches!(err, Err(BundleError::UnknownCfg { .. })));
     }
 }
+
+#[test]
+fn error_messages() {
+    let dir = crate::temp::TempDir::new("resolve-errors").unwrap();
+    let dir = dir.path();
+    let lib_rs = dir.join("lib.rs");
+    let error = |src: &str| {
+        fs::write(&lib_rs, src).unwrap();
+        let err = resolve_nested_mod(&lib_rs, &Cfg::default()).err().unwrap();
+        err.to_string()
+    };
+
+    let missing = dir.join("missing.rs");
+    let message = error("mod missing;");
+    let expected = format!("failed to read module {}: ", missing.display());
+    assert!(message.starts_with(&expected), "{message}");
+
+    let message = error("fn f() {}\nlet x = 1;\n");
+    let expected = format!("{}:2:1: ", lib_rs.display());
+    assert!(message.starts_with(&expected), "{message}");
+
+    let no_lib_rs = dir.join("none.rs");
+    let err = resolve_nested_mod(&no_lib_rs, &Cfg::default()).err().unwrap();
+    let expected = format!("failed to read {}: ", no_lib_rs.display());
+    assert!(err.to_string().starts_with(&expected), "{err}");
+}
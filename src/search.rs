@@ -0,0 +1,172 @@
+use std::path::Path;
+
+use quote::quote;
+use syn::{Item, parse_file, spanned::Spanned};
+
+use crate::{error::BundleError, suggest::levenshtein};
+
+/// An item found by `Library::search`.
+#[derive(Debug)]
+pub struct Hit {
+    /// The root name of the library, such as `nekolib`.
+    pub root: String,
+    /// `[category, crate, ident]`
+    pub path: Vec<String>,
+    /// The declaration without the body, such as
+    /// `fn gcd(a: u64, b: u64) -> u64` or `macro_rules! fw`.
+    pub signature: String,
+}
+
+impl std::fmt::Display for Hit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = self.path.join("::");
+        write!(f, "use {}::{path}; // {}", self.root, self.signature)
+    }
+}
+
+/// Returns how well `ident` matches `query`, where smaller is better, or
+/// `None` if they are too different.  Case is ignored.
+pub(crate) fn score(query: &str, ident: &str) -> Option<usize> {
+    let query = query.to_lowercase();
+    let ident = ident.to_lowercase();
+    if query == ident {
+        Some(0)
+    } else if ident.contains(&query) {
+        Some(1)
+    } else {
+        let d = levenshtein(&query, &ident);
+        (d <= (query.chars().count() / 3).max(1)).then_some(1 + d)
+    }
+}
+
+/// Returns the signature of the public item `ident` in the
+/// file at `src_lib_path`.
+pub(crate) fn signature(
+    src_lib_path: &Path,
+    ident: &str,
+) -> Result<Option<String>, BundleError> {
+    let src = std::fs::read_to_string(src_lib_path)
+        .map_err(|e| BundleError::io("read", src_lib_path, e))?;
+    let ast = parse_file(&src)
+        .map_err(|e| BundleError::source_parse(Some(src_lib_path), e))?;
+    Ok(ast.items.iter().find_map(|item| item_signature(item, ident)))
+}
+
+fn item_signature(item: &Item, ident: &str) -> Option<String> {
+    let tokens = match item {
+        Item::Const(item) if item.ident == ident => {
+            let syn::ItemConst { const_token, ident, ty, .. } = item;
+            quote! { #const_token #ident: #ty }
+        }
+        Item::Enum(item) if item.ident == ident => {
+            let syn::ItemEnum { enum_token, ident, generics, .. } = item;
+            quote! { #enum_token #ident #generics }
+        }
+        Item::Fn(item) if item.sig.ident == ident => {
+            let sig = &item.sig;
+            quote! { #sig }
+        }
+        Item::Macro(item)
+            if item.ident.as_ref().is_some_and(|i| i == ident) =>
+        {
+            return Some(format!("macro_rules! {ident}"));
+        }
+        Item::Mod(item) if item.ident == ident => {
+            return Some(format!("mod {ident}"));
+        }
+        Item::Static(item) if item.ident == ident => {
+            let syn::ItemStatic { static_token, mutability, ident, ty, .. } =
+                item;
+            quote! { #static_token #mutability #ident: #ty }
+        }
+        Item::Struct(item) if item.ident == ident => {
+            let syn::ItemStruct { struct_token, ident, generics, .. } = item;
+            quote! { #struct_token #ident #generics }
+        }
+        Item::Trait(item) if item.ident == ident => {
+            let syn::ItemTrait {
+                unsafety,
+                trait_token,
+                ident,
+                generics,
+                colon_token,
+                supertraits,
+                ..
+            } = item;
+            quote! {
+                #unsafety #trait_token #ident #generics #colon_token #supertraits
+            }
+        }
+        Item::TraitAlias(item) if item.ident == ident => {
+            let syn::ItemTraitAlias { trait_token, ident, generics, .. } = item;
+            quote! { #trait_token #ident #generics }
+        }
+        Item::Type(item) if item.ident == ident => {
+            let syn::ItemType { type_token, ident, generics, ty, .. } = item;
+            quote! { #type_token #ident #generics = #ty }
+        }
+        Item::Union(item) if item.ident == ident => {
+            let syn::ItemUnion { union_token, ident, generics, .. } = item;
+            quote! { #union_token #ident #generics }
+        }
+        // re-exported from elsewhere
+        Item::Use(item) if uses(&item.tree, ident) => {
+            let syn::ItemUse { use_token, tree, .. } = item;
+            quote! { #use_token #tree }
+        }
+        _ => return None,
+    };
+    // the original text if available, which is better spaced
+    let text =
+        tokens.span().source_text().unwrap_or_else(|| tokens.to_string());
+    Some(text.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+fn uses(tree: &syn::UseTree, ident: &str) -> bool {
+    match tree {
+        syn::UseTree::Path(path) => uses(&path.tree, ident),
+        syn::UseTree::Name(name) => name.ident == ident,
+        syn::UseTree::Rename(rename) => rename.rename == ident,
+        syn::UseTree::Glob(_) => false,
+        syn::UseTree::Group(group) => {
+            group.items.iter().any(|tree| uses(tree, ident))
+        }
+    }
+}
+
+#[test]
+fn scoring() {
+    assert_eq!(score("UnionFind", "UnionFind"), Some(0));
+    assert_eq!(score("union", "UnionFind"), Some(1));
+    assert_eq!(score("UnoinFind", "UnionFind"), Some(3));
+    assert_eq!(score("gcd", "gcm"), Some(2));
+    assert_eq!(score("gcd", "lcm"), None);
+}
+
+#[test]
+fn signatures() {
+    let src = r#"
+pub fn gcd(a: u64,
+           b: u64) -> u64 { if b == 0 { a } else { gcd(b, a % b) } }
+pub struct Fenwick<T: Clone> { buf: Vec<T> }
+pub trait Monoid: Sized { fn id() -> Self; }
+#[macro_export]
+macro_rules! fw { () => {} }
+mod wrap { pub fn lcm3() {} }
+pub use wrap::{lcm3, lcm3 as lcm_three};
+"#;
+    let ast = parse_file(src).unwrap();
+    let sig =
+        |ident| ast.items.iter().find_map(|item| item_signature(item, ident));
+
+    assert_eq!(sig("gcd").unwrap(), "fn gcd(a: u64, b: u64) -> u64");
+    assert_eq!(sig("Fenwick").unwrap(), "struct Fenwick<T: Clone>");
+    assert_eq!(sig("Monoid").unwrap(), "trait Monoid: Sized");
+    assert_eq!(sig("fw").unwrap(), "macro_rules! fw");
+    assert_eq!(sig("wrap").unwrap(), "mod wrap");
+    assert_eq!(
+        sig("lcm_three").unwrap(),
+        "use wrap::{lcm3, lcm3 as lcm_three}"
+    );
+    assert_eq!(sig("buf"), None);
+}
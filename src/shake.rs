@@ -0,0 +1,329 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    ops::RangeInclusive,
+};
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::{Item, parse_file, spanned::Spanned};
+
+use crate::{error::BundleError, library::Crate, source::dfs_use_tree};
+
+pub struct Shaken {
+    pub code: String,
+    /// Names provided by the kept items, e.g. `UnionFind` or `macro_name`.
+    pub kept: BTreeSet<String>,
+    /// The line in the input code each line of `code` comes from.
+    pub lines: Vec<usize>,
+}
+
+struct Node {
+    cr: Crate,
+    text: String,
+    lines: RangeInclusive<usize>,
+    // names this item makes visible to others
+    provides: Vec<String>,
+    // identifiers appearing anywhere in this item
+    refs: BTreeSet<String>,
+    // for impl blocks: identifiers in `impl<..> Trait for Type where ..`
+    header: Option<BTreeSet<String>>,
+    // `macro_rules!` bodies are expanded outside their crate
+    global: bool,
+    root: bool,
+}
+
+/// Keeps only the items reachable from `user` code.
+///
+/// Reachability is computed by identifiers: an item is kept once its name
+/// appears in the user's code, or in a kept item of the same crate or of a
+/// crate depending on it.  An `impl` block is kept when every library item
+/// named in its header is kept.  Names are matched without resolving paths,
+/// so the result is an over-approximation.
+pub fn shake(
+    user: &str,
+    crates: &BTreeMap<Crate, String>,
+    deps_1: &BTreeMap<Crate, Vec<Crate>>,
+) -> Result<BTreeMap<Crate, Shaken>, BundleError> {
+    // the code of a crate is not a file of the library any longer
+    let crate_error = |cr: &Crate, e: syn::Error| {
+        let message = format!("in the bundled code of `{cr}`: {e}");
+        BundleError::source_parse(None, syn::Error::new(e.span(), message))
+    };
+    let mut nodes = vec![];
+    for (cr, code) in crates {
+        let ast = parse_file(code).map_err(|e| crate_error(cr, e))?;
+        for item in &ast.items {
+            nodes.push(new_node(cr, item).map_err(|e| crate_error(cr, e))?);
+        }
+    }
+
+    let mut by_name = BTreeMap::<_, Vec<_>>::new();
+    for (i, node) in nodes.iter().enumerate() {
+        for name in &node.provides {
+            by_name.entry(name.as_str()).or_default().push(i);
+        }
+    }
+
+    let in_scope = |scope: Option<&Crate>, cr: &Crate| match scope {
+        None => true,
+        Some(scope) => {
+            scope == cr || deps_1.get(scope).is_some_and(|v| v.contains(cr))
+        }
+    };
+
+    let mut kept = vec![false; nodes.len()];
+    let mut queue = VecDeque::new();
+    let mut seen = BTreeSet::new();
+
+    let visit = |i: usize, kept: &mut [bool], queue: &mut VecDeque<_>| {
+        kept[i] = true;
+        let node = &nodes[i];
+        let scope = (!node.global).then_some(&node.cr);
+        for name in &node.refs {
+            queue.push_back((scope, name.as_str()));
+        }
+    };
+
+    let user_idents = collect_idents(
+        syn::parse_str(user).map_err(|e| BundleError::source_parse(None, e))?,
+    );
+    queue.extend(user_idents.iter().map(|name| (None, name.as_str())));
+    for (i, node) in nodes.iter().enumerate() {
+        if node.root {
+            visit(i, &mut kept, &mut queue);
+        }
+    }
+
+    loop {
+        while let Some((scope, name)) = queue.pop_front() {
+            if !seen.insert((scope, name)) {
+                continue;
+            }
+            for &i in by_name.get(name).into_iter().flatten() {
+                if !kept[i] && in_scope(scope, &nodes[i].cr) {
+                    visit(i, &mut kept, &mut queue);
+                }
+            }
+        }
+
+        let mut changed = false;
+        for i in 0..nodes.len() {
+            let Some(header) = nodes[i].header.as_ref().filter(|_| !kept[i])
+            else {
+                continue;
+            };
+            let cr = &nodes[i].cr;
+            let ok = header.iter().all(|name| {
+                let mut providers = by_name
+                    .get(name.as_str())
+                    .into_iter()
+                    .flatten()
+                    .filter(|&&j| in_scope(Some(cr), &nodes[j].cr))
+                    .peekable();
+                providers.peek().is_none() || providers.any(|&j| kept[j])
+            });
+            if ok {
+                visit(i, &mut kept, &mut queue);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut res: BTreeMap<_, _> = crates
+        .keys()
+        .map(|cr| {
+            let shaken = Shaken {
+                code: "".to_owned(),
+                kept: BTreeSet::new(),
+                lines: vec![],
+            };
+            (cr.clone(), shaken)
+        })
+        .collect();
+    for (node, _) in nodes.iter().zip(&kept).filter(|&(_, &k)| k) {
+        let shaken = res.get_mut(&node.cr).unwrap();
+        shaken.code += &node.text;
+        shaken.code += "\n";
+        shaken.kept.extend(node.provides.iter().cloned());
+        shaken.lines.extend(node.lines.clone());
+    }
+    Ok(res)
+}
+
+fn new_node(cr: &Crate, item: &Item) -> Result<Node, syn::Error> {
+    let mut provides = vec![];
+    let mut header = None;
+    let mut global = false;
+    let mut root = false;
+
+    match item {
+        Item::Const(item) => provides.push(item.ident.to_string()),
+        Item::Enum(item) => provides.push(item.ident.to_string()),
+        Item::Fn(item) => provides.push(item.sig.ident.to_string()),
+        Item::Mod(item) => provides.push(item.ident.to_string()),
+        Item::Static(item) => provides.push(item.ident.to_string()),
+        Item::Struct(item) => provides.push(item.ident.to_string()),
+        Item::TraitAlias(item) => provides.push(item.ident.to_string()),
+        Item::Type(item) => provides.push(item.ident.to_string()),
+        Item::Union(item) => provides.push(item.ident.to_string()),
+        Item::ExternCrate(item) => {
+            let ident = item.rename.as_ref().map_or(&item.ident, |(_, i)| i);
+            provides.push(ident.to_string());
+        }
+        Item::Trait(item) => {
+            // methods may be called without naming the trait
+            provides.push(item.ident.to_string());
+            for item in &item.items {
+                match item {
+                    syn::TraitItem::Const(item) => {
+                        provides.push(item.ident.to_string())
+                    }
+                    syn::TraitItem::Fn(item) => {
+                        provides.push(item.sig.ident.to_string())
+                    }
+                    syn::TraitItem::Type(item) => {
+                        provides.push(item.ident.to_string())
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Item::Macro(item) => match &item.ident {
+            Some(ident) => {
+                provides.push(ident.to_string());
+                global = true;
+            }
+            // macro invocations may expand to anything
+            None => root = true,
+        },
+        Item::Use(item) => {
+            for u in dfs_use_tree(&item.tree, &mut vec![]) {
+                match u.last().map(String::as_str) {
+                    Some("*") => root = true,
+                    Some("self") if u.len() >= 2 => {
+                        provides.push(u[u.len() - 2].clone())
+                    }
+                    Some(leaf) => provides.push(leaf.to_owned()),
+                    None => {}
+                }
+            }
+        }
+        Item::Impl(item) => {
+            let mut ts = item.generics.to_token_stream();
+            if let Some((_, path, _)) = &item.trait_ {
+                ts.extend(path.to_token_stream());
+            }
+            ts.extend(item.self_ty.to_token_stream());
+            ts.extend(item.generics.where_clause.to_token_stream());
+            header = Some(collect_idents(ts));
+        }
+        _ => root = true,
+    }
+
+    let text = item.span().source_text().ok_or_else(|| {
+        syn::Error::new(item.span(), "the source of the item is unknown")
+    })?;
+    Ok(Node {
+        cr: cr.clone(),
+        text,
+        lines: item.span().start().line..=item.span().end().line,
+        provides,
+        refs: collect_idents(item.to_token_stream()),
+        header,
+        global,
+        root,
+    })
+}
+
+fn collect_idents(ts: TokenStream) -> BTreeSet<String> {
+    fn dfs(ts: TokenStream, res: &mut BTreeSet<String>) {
+        for tt in ts {
+            match tt {
+                TokenTree::Ident(ident) => {
+                    res.insert(ident.to_string());
+                }
+                TokenTree::Group(group) => dfs(group.stream(), res),
+                _ => {}
+            }
+        }
+    }
+
+    let mut res = BTreeSet::new();
+    dfs(ts, &mut res);
+    res
+}
+
+#[test]
+fn reachability() {
+    use proc_macro2::TokenStream;
+
+    let ds = Crate::new("ds".to_owned(), "foo".to_owned());
+    let math = Crate::new("math".to_owned(), "bar".to_owned());
+
+    let foo = r#"
+use bar::gcd;
+pub struct Foo(u32);
+impl Foo {
+    pub fn new(x: u32) -> Self { Self(gcd(x, 6)) }
+}
+impl std::fmt::Display for Foo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+pub struct Unused;
+impl Unused {
+    fn unused() -> Self { Self }
+}
+macro_rules! foo {
+    () => { $crate::nekolib::ds::foo::Foo::new(1) }
+}
+"#;
+    let bar = r#"
+pub fn gcd(x: u32, y: u32) -> u32 { if y == 0 { x } else { gcd(y, x % y) } }
+pub fn lcm(x: u32, y: u32) -> u32 { x / gcd(x, y) * y }
+pub trait Gcd { fn gcd(self, other: Self) -> Self; }
+impl Gcd for u32 { fn gcd(self, other: Self) -> Self { gcd(self, other) } }
+"#;
+    let user = "fn main() { println!(\"{}\", foo!()); }";
+
+    let crates = [(ds.clone(), foo.to_owned()), (math.clone(), bar.to_owned())]
+        .into_iter()
+        .collect();
+    let deps_1 = [(ds.clone(), vec![math.clone()])].into_iter().collect();
+
+    let actual = shake(user, &crates, &deps_1).unwrap();
+
+    let expected_foo = r#"
+use bar::gcd;
+pub struct Foo(u32);
+impl Foo {
+    pub fn new(x: u32) -> Self { Self(gcd(x, 6)) }
+}
+impl std::fmt::Display for Foo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+macro_rules! foo {
+    () => { $crate::nekolib::ds::foo::Foo::new(1) }
+}
+"#;
+    // `Gcd` is kept since its method name `gcd` is referenced.
+    let expected_bar = r#"
+pub fn gcd(x: u32, y: u32) -> u32 { if y == 0 { x } else { gcd(y, x % y) } }
+pub trait Gcd { fn gcd(self, other: Self) -> Self; }
+impl Gcd for u32 { fn gcd(self, other: Self) -> Self { gcd(self, other) } }
+"#;
+
+    for (cr, expected) in [(&ds, expected_foo), (&math, expected_bar)] {
+        let actual: TokenStream = actual[cr].code.parse().unwrap();
+        let expected: TokenStream = expected.parse().unwrap();
+        assert_eq!(actual.to_string(), expected.to_string());
+    }
+    assert!(actual[&ds].kept.contains("foo"));
+    assert!(!actual[&math].kept.contains("lcm"));
+}
@@ -1,36 +1,179 @@
-use syn::parse_file;
+use proc_macro2::{TokenStream, TokenTree};
+use syn::{
+    parse_file,
+    spanned::Spanned,
+    visit::{self, Visit},
+};
+
+use crate::{error::BundleError, library::BUNDLED_MARKER, polish::offset_fn};
 
 pub struct Source {
     content: String,
 }
 
+/// The header of a module bundled by a previous run.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BundledHeader {
+    /// The root name of the library, as `nekolib`.
+    pub name: String,
+    /// The commit of the library, as written by `Metadata`.
+    pub commit: String,
+}
+
 impl Source {
     pub fn new(content: String) -> Self { Self { content } }
 
     pub fn get_code(&self) -> &str { &self.content }
 
-    pub fn get_uses(&self) -> Vec<Vec<String>> {
-        let ast = parse_file(&self.content).unwrap();
-        let mut res: Vec<_> =
-            ast.items
-                .into_iter()
-                .filter_map(|i| {
-                    if let syn::Item::Use(u) = i { Some(u) } else { None }
-                })
-                .flat_map(|u| {
-                    let mut cur = vec![];
-                    if u.leading_colon.is_some() {
-                        cur.push("".to_owned());
-                    }
-                    dfs_use_tree(&u.tree, &mut cur)
-                })
-                .collect();
+    /// Returns every `{name}::...` path written in the source, without the
+    /// leading `name`, such as `nekolib`.
+    ///
+    /// This includes `use` declarations at any nesting level, and paths in
+    /// expressions, types and macro invocations such as
+    /// `nekolib::math::gcd(a, b)`.  Glob imports end with `"*"`.
+    pub fn get_uses(
+        &self,
+        name: &str,
+    ) -> Result<Vec<Vec<String>>, BundleError> {
+        let ast = parse_file(&self.content)
+            .map_err(|e| BundleError::source_parse(None, e))?;
+        let mut visitor = CollectPaths { res: vec![] };
+        visitor.visit_file(&ast);
 
+        let mut res = visitor.res;
         res.sort_unstable();
         res.dedup();
-        res.into_iter()
-            .filter_map(|u| (u[0] == "nekolib").then(|| u[1..].to_vec()))
-            .collect()
+        let res = res
+            .into_iter()
+            .filter_map(|u| {
+                (u.len() >= 2 && u[0] == name).then(|| u[1..].to_vec())
+            })
+            .collect();
+        Ok(res)
+    }
+
+    /// Splits the source into the user's code and the modules bundled by a
+    /// previous run, which are recognized by the doc comment with
+    /// `BUNDLED_MARKER` and the commit line.
+    ///
+    /// The user's code is returned as it was before bundling, so that
+    /// bundling it again gives the same result.
+    pub fn split_bundled(
+        &self,
+    ) -> Result<(Self, Vec<BundledHeader>), BundleError> {
+        let ast = parse_file(&self.content)
+            .map_err(|e| BundleError::source_parse(None, e))?;
+        let offset = offset_fn(&self.content);
+
+        let mut headers = vec![];
+        let mut ranges = vec![];
+        for item in &ast.items {
+            let syn::Item::Mod(item) = item else { continue };
+            let Some(header) = bundled_header(item) else { continue };
+            let span = item.span();
+            ranges.push((offset(span.start()), offset(span.end())));
+            headers.push(header);
+        }
+        if headers.is_empty() {
+            return Ok((Self::new(self.content.clone()), headers));
+        }
+
+        let src = &self.content;
+        let mut res = "".to_owned();
+        let mut pos = 0;
+        for (start, end) in ranges {
+            // `Library::bundle` puts a newline before the module and after
+            let start = src[..start].strip_suffix('\n').map_or(start, str::len);
+            res += &src[pos..start];
+            pos = if src[end..].starts_with('\n') { end + 1 } else { end };
+        }
+        // the newline by `println!`
+        if !src[pos..].trim().is_empty() {
+            res += &src[pos..];
+        }
+        Ok((Self::new(res), headers))
+    }
+}
+
+fn bundled_header(item: &syn::ItemMod) -> Option<BundledHeader> {
+    let docs: Vec<_> = (item.attrs.iter())
+        .filter_map(|attr| match &attr.meta {
+            syn::Meta::NameValue(syn::MetaNameValue {
+                path,
+                value:
+                    syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(s), .. }),
+                ..
+            }) if path.is_ident("doc") => Some(s.value()),
+            _ => None,
+        })
+        .collect();
+    if docs.first()?.trim() != BUNDLED_MARKER {
+        return None;
+    }
+    let commit =
+        docs.iter().find_map(|doc| doc.trim().strip_prefix("Commit:"))?;
+    Some(BundledHeader {
+        name: item.ident.to_string(),
+        commit: commit.trim().to_owned(),
+    })
+}
+
+struct CollectPaths {
+    res: Vec<Vec<String>>,
+}
+
+impl<'ast> Visit<'ast> for CollectPaths {
+    fn visit_item_use(&mut self, node: &'ast syn::ItemUse) {
+        let mut cur = vec![];
+        if node.leading_colon.is_some() {
+            cur.push("".to_owned());
+        }
+        self.res.extend(dfs_use_tree(&node.tree, &mut cur));
+    }
+
+    fn visit_path(&mut self, node: &'ast syn::Path) {
+        if node.leading_colon.is_none() {
+            let path = node.segments.iter().map(|s| s.ident.to_string());
+            self.res.push(path.collect());
+        }
+        visit::visit_path(self, node);
+    }
+
+    fn visit_macro(&mut self, node: &'ast syn::Macro) {
+        visit::visit_macro(self, node);
+        token_paths(node.tokens.clone(), &mut self.res);
+    }
+}
+
+/// Collects `a::b::c` sequences from tokens that are not parsed by `syn`,
+/// e.g. arguments of `println!`.
+fn token_paths(ts: TokenStream, res: &mut Vec<Vec<String>>) {
+    let tts: Vec<_> = ts.into_iter().collect();
+    let is_colon = |i: usize| match tts.get(i) {
+        Some(TokenTree::Punct(p)) => p.as_char() == ':',
+        _ => false,
+    };
+
+    let mut i = 0;
+    while i < tts.len() {
+        match &tts[i] {
+            TokenTree::Group(group) => token_paths(group.stream(), res),
+            TokenTree::Ident(ident) if !(i >= 2 && is_colon(i - 1)) => {
+                let mut path = vec![ident.to_string()];
+                while is_colon(i + 1) && is_colon(i + 2) {
+                    match tts.get(i + 3) {
+                        Some(TokenTree::Ident(ident)) => {
+                            path.push(ident.to_string());
+                            i += 3;
+                        }
+                        _ => break,
+                    }
+                }
+                res.push(path);
+            }
+            _ => {}
+        }
+        i += 1;
     }
 }
 
@@ -76,3 +219,83 @@ pub fn dfs_use_tree(
     dfs(u, cur, &mut res);
     res
 }
+
+#[test]
+fn nested_uses() {
+    let src = r#"
+use nekolib::ds::*;
+use std::io::Read;
+
+mod solver {
+    use nekolib::{algo::bisect, math::gcd::*};
+}
+
+fn main() {
+    use nekolib::seq::Rle;
+    let g = nekolib::math::gcd(4, 6);
+    let v: nekolib::ds::UnionFind = nekolib::ds::UnionFind::new(3);
+    println!("{}", nekolib::math::lcm(4, 6));
+}
+"#;
+
+    let actual = Source::new(src.to_owned()).get_uses("nekolib").unwrap();
+    let expected = [
+        &["algo", "bisect"][..],
+        &["ds", "*"],
+        &["ds", "UnionFind"],
+        &["ds", "UnionFind", "new"],
+        &["math", "gcd"],
+        &["math", "gcd", "*"],
+        &["math", "lcm"],
+        &["seq", "Rle"],
+    ];
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn bundled_modules() {
+    let user = r#"use nekolib::math::gcd;
+
+fn main() { println!("{}", gcd(4, 6)); }
+"#;
+    let bundled = format!(
+        r#"{user}
+/// This module is bundled automatically.
+/// See <https://rsk0315.github.io/nekolib/nekolib_doc/index.html> for documentation.
+/// Commit: 0123abcd-dirty
+#[allow(unused)]
+#[allow(private_interfaces)]
+pub mod nekolib {{pub mod math {{
+    pub mod gcd {{
+pub fn gcd (a : u64 , b : u64) -> u64 {{ if b == 0 {{ a }} else {{ gcd (b , a % b) }} }}
+    }}
+    #[allow(unused_imports)]
+    pub use gcd::*;
+}}
+}}
+
+/// This module is bundled automatically.
+/// Commit: 4567ef
+#[allow(unused)]
+#[allow(private_interfaces)]
+pub mod snippets {{}}
+
+"#
+    );
+
+    let (source, headers) = Source::new(bundled).split_bundled().unwrap();
+    assert_eq!(source.get_code(), user);
+    let header = |name: &str, commit: &str| BundledHeader {
+        name: name.to_owned(),
+        commit: commit.to_owned(),
+    };
+    assert_eq!(
+        headers,
+        [header("nekolib", "0123abcd-dirty"), header("snippets", "4567ef")]
+    );
+
+    let (source, headers) =
+        Source::new(user.to_owned()).split_bundled().unwrap();
+    assert_eq!(source.get_code(), user);
+    assert!(headers.is_empty());
+}
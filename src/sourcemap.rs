@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::error::BundleError;
+
+/// Maps lines of the bundled code to the user's code and the library
+/// files.
+#[derive(Debug, Serialize)]
+pub struct SourceMap {
+    pub mappings: Vec<Mapping>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Mapping {
+    /// Lines of the bundled code, 1-indexed and inclusive.
+    pub lines: [usize; 2],
+    /// `None` for the user's code.
+    #[serde(rename = "crate", skip_serializing_if = "Option::is_none")]
+    pub cr: Option<String>,
+    pub path: PathBuf,
+    /// The original line of `lines[0]`.  The following lines correspond
+    /// one by one.
+    pub line: usize,
+}
+
+impl SourceMap {
+    /// Fails if a path is not valid UTF-8, which JSON cannot represent.
+    pub fn to_json(&self) -> Result<String, BundleError> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| BundleError::SourceMap { message: e.to_string() })
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_path() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let path = PathBuf::from(OsStr::from_bytes(b"nekolib-src/\xff/lib.rs"));
+    let cr = Some("ds::fenwick".to_owned());
+    let mapping = Mapping { lines: [1, 1], cr, path, line: 1 };
+    let source_map = SourceMap { mappings: vec![mapping] };
+    assert!(matches!(source_map.to_json(), Err(BundleError::SourceMap { .. })));
+}
@@ -0,0 +1,93 @@
+use std::collections::BTreeSet;
+
+/// Suggests at most `SUGGEST_MAX` paths close to an unresolved path `u`.
+///
+/// Each `key` is `[category, crate, ident]`.  The last one or two segments
+/// of `u` are compared with the identifier (and the crate name), so that
+/// both `ds::UnionFnd` and `math::UnionFind` suggest
+/// `ds::union_find::UnionFind`.
+pub fn suggest<'a>(
+    u: &[String],
+    keys: impl IntoIterator<Item = &'a [String]>,
+) -> Vec<Vec<String>> {
+    const SUGGEST_MAX: usize = 3;
+
+    let Some(cat) = u.first() else { return vec![] };
+    let mut res = BTreeSet::new();
+    for key in keys {
+        let [key_cat, key_cr, key_ident] = key else { continue };
+
+        let mut score = usize::MAX;
+        if let Some(ident) = u.get(1) {
+            score = score.min(levenshtein(ident, key_ident));
+        }
+        if let (Some(cr), Some(ident)) = (u.get(1), u.get(2)) {
+            let d = levenshtein(ident, key_ident) + levenshtein(cr, key_cr);
+            score = score.min(d);
+        }
+        if score == usize::MAX {
+            continue;
+        }
+        if cat != key_cat {
+            score += 1;
+        }
+
+        if score <= (key_ident.len() / 3).max(2) {
+            res.insert((score, key.to_vec()));
+        }
+    }
+
+    res.into_iter().take(SUGGEST_MAX).map(|(_, key)| key).collect()
+}
+
+pub fn levenshtein(s: &str, t: &str) -> usize {
+    let s: Vec<_> = s.chars().collect();
+    let t: Vec<_> = t.chars().collect();
+
+    let mut dp: Vec<_> = (0..=t.len()).collect();
+    for i in 1..=s.len() {
+        let mut prev = dp[0];
+        dp[0] = i;
+        for j in 1..=t.len() {
+            let cur = dp[j];
+            dp[j] = if s[i - 1] == t[j - 1] {
+                prev
+            } else {
+                1 + prev.min(dp[j]).min(dp[j - 1])
+            };
+            prev = cur;
+        }
+    }
+    dp[t.len()]
+}
+
+#[test]
+fn suggestion() {
+    let to_vec =
+        |s: &str| -> Vec<String> { s.split("::").map(str::to_owned).collect() };
+    let keys: Vec<_> = [
+        "ds::union_find::UnionFind",
+        "ds::fenwick::Fenwick",
+        "math::gcd::gcd",
+        "math::gcd::lcm",
+    ]
+    .into_iter()
+    .map(to_vec)
+    .collect();
+    let keys = || keys.iter().map(|k| k.as_slice());
+
+    let test = |u: &str| -> Vec<String> {
+        let actual = suggest(&to_vec(u), keys());
+        actual.into_iter().map(|s| s.join("::")).collect()
+    };
+
+    assert_eq!(test("ds::UnionFnd"), ["ds::union_find::UnionFind"]);
+    assert_eq!(test("math::UnionFind"), ["ds::union_find::UnionFind"]);
+    assert_eq!(test("ds::unionfind::UnionFind"), ["ds::union_find::UnionFind"]);
+    assert_eq!(test("math::gdc"), ["math::gcd::gcd"]);
+    assert_eq!(test("math::lcd"), ["math::gcd::gcd", "math::gcd::lcm"]);
+    assert!(test("graph::Dijkstra").is_empty());
+
+    assert_eq!(levenshtein("kitten", "sitting"), 3);
+    assert_eq!(levenshtein("", "abc"), 3);
+}
@@ -0,0 +1,34 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::error::BundleError;
+
+/// A directory in the system's temporary directory, removed with its
+/// contents when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates `nekolib-bundle-{name}-{pid}`.  The path is canonicalized,
+    /// as the paths in the library index are.
+    pub fn new(name: &str) -> Result<Self, BundleError> {
+        let path = std::env::temp_dir()
+            .join(format!("nekolib-bundle-{name}-{}", std::process::id()));
+        fs::create_dir_all(&path)
+            .map_err(|e| BundleError::io("create", &path, e))?;
+        let mut res = Self { path };
+        let path = res.path.canonicalize();
+        res.path =
+            path.map_err(|e| BundleError::io("resolve", &res.path, e))?;
+        Ok(res)
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) { let _ = fs::remove_dir_all(&self.path); }
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as _};
+
+use crate::error::BundleError;
+
+/// Modifications within this interval are put together, as editors may
+/// write a file in several steps.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Watches files for modifications, with inotify on Linux.
+///
+/// The directories containing the files are watched instead of the files
+/// themselves, since editors often save a file by renaming another one
+/// onto it.
+pub struct Watcher {
+    watcher: RecommendedWatcher,
+    rx: Receiver<notify::Result<Event>>,
+    files: BTreeSet<PathBuf>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl Watcher {
+    pub fn new() -> Result<Self, BundleError> {
+        let (tx, rx) = mpsc::channel();
+        let watcher = notify::recommended_watcher(tx).map_err(error)?;
+        let files = BTreeSet::new();
+        let dirs = BTreeSet::new();
+        Ok(Self { watcher, rx, files, dirs })
+    }
+
+    /// Watches `files` instead of the ones watched so far.  Missing files
+    /// are ignored.
+    pub fn watch(
+        &mut self,
+        files: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Result<(), BundleError> {
+        self.files = (files.into_iter())
+            .filter_map(|path| path.as_ref().canonicalize().ok())
+            .collect();
+        let dirs: BTreeSet<_> = (self.files.iter())
+            .filter_map(|path| Some(path.parent()?.to_owned()))
+            .collect();
+        for dir in self.dirs.difference(&dirs) {
+            // it may have been removed
+            let _ = self.watcher.unwatch(dir);
+        }
+        for dir in dirs.difference(&self.dirs) {
+            self.watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .map_err(error)?;
+        }
+        self.dirs = dirs;
+        Ok(())
+    }
+
+    /// Blocks until some of the watched files are modified, and returns
+    /// them.
+    pub fn wait(&mut self) -> Result<Vec<PathBuf>, BundleError> {
+        let mut changed = BTreeSet::new();
+        while changed.is_empty() {
+            let event = self.rx.recv().map_err(error)?;
+            self.collect(event.map_err(error)?, &mut changed);
+        }
+        loop {
+            match self.rx.recv_timeout(DEBOUNCE) {
+                Ok(event) => self.collect(event.map_err(error)?, &mut changed),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(e @ RecvTimeoutError::Disconnected) => {
+                    return Err(error(e));
+                }
+            }
+        }
+        Ok(changed.into_iter().collect())
+    }
+
+    fn collect(&self, event: Event, changed: &mut BTreeSet<PathBuf>) {
+        if event.kind.is_create() || event.kind.is_modify() {
+            let paths = event.paths.into_iter();
+            changed.extend(paths.filter(|path| self.files.contains(path)));
+        }
+    }
+}
+
+fn error(e: impl ToString) -> BundleError {
+    BundleError::Watch { message: e.to_string() }
+}
+
+#[test]
+fn modification() {
+    let dir = crate::temp::TempDir::new("watch").unwrap();
+    let dir = dir.path();
+    let (main, other) = (dir.join("main.rs"), dir.join("other.rs"));
+    std::fs::write(&main, "").unwrap();
+    let main = main.canonicalize().unwrap();
+
+    let mut watcher = Watcher::new().unwrap();
+    watcher.watch([&main]).unwrap();
+    std::fs::write(&other, "").unwrap();
+    // saved by renaming
+    let tmp = dir.join("main.rs.tmp");
+    std::fs::write(&tmp, "fn main() {}").unwrap();
+    std::fs::rename(&tmp, &main).unwrap();
+    assert_eq!(watcher.wait().unwrap(), [main]);
+}